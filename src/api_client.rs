@@ -1,19 +1,54 @@
 use crate::logging::Logger;
 use std::{
+    cell::RefCell,
+    collections::BTreeMap,
     fmt::{Display, Formatter},
-    fs::File,
-    io::Read,
-    net::{IpAddr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     process,
     str::FromStr,
     rc::Rc,
+    time::Instant,
 };
 
 use futures::future;
 use reqwest::{header, RequestBuilder};
-use yaml_rust::{Yaml, YamlLoader};
 
-use crate::ip_checker;
+use crate::arg_parser::ConfigFormat;
+use crate::config::{self, Entry, Provider};
+use crate::resolver::Resolver;
+use crate::state::{self, State};
+use crate::metrics;
+use crate::status::{RecordReport, RecordStatus};
+use crate::ip_checker::{self, Family, IpSource};
+
+/// How long after publishing an address DNS is not consulted, since resolvers may still
+/// serve the previous record.
+const PROPAGATION_WINDOW_SECS: u64 = 3600;
+
+/// What the state file says about the detected address of a record
+#[derive(Debug, PartialEq)]
+enum StateCheck {
+    /// The address differs from the one last published
+    Changed,
+    /// The address was published within the propagation window; DNS may still lag behind
+    Settling,
+    /// The address was published long enough ago that DNS should serve it
+    CrossCheck,
+}
+
+/// Compares the `actual` address with the one last `published`.
+fn state_check(published: &state::Published, actual: IpAddr) -> StateCheck {
+    if published.ip != actual {
+        StateCheck::Changed
+    } else if published.age_secs() < PROPAGATION_WINDOW_SECS {
+        StateCheck::Settling
+    } else {
+        StateCheck::CrossCheck
+    }
+}
+
+/// Upper bound on a provider request, so a hung connection cannot stall a daemon cycle
+const HTTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
 
 #[derive(Debug)]
 struct Credentials {
@@ -32,7 +67,7 @@ impl Credentials {
 }
 
 #[derive(Debug)]
-enum Method {
+pub enum Method {
     Post,
     Put,
     Delete,
@@ -61,11 +96,20 @@ impl FromStr for Method {
     }
 }
 #[derive(Debug)]
-enum Record {
+pub enum Record {
     A,
     Aaaa,
 }
 
+impl Record {
+    fn family(&self) -> Family {
+        match self {
+            Record::A => Family::V4,
+            Record::Aaaa => Family::V6,
+        }
+    }
+}
+
 impl Display for Record {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         match self {
@@ -87,6 +131,40 @@ impl FromStr for Record {
     }
 }
 
+/// How an entry decides whether its records need updating
+#[derive(Debug, PartialEq)]
+pub enum Comparison {
+    /// Look the domain up through the (recursive) resolver
+    Dns,
+    /// Query the zone's authoritative nameservers directly, bypassing caches
+    Authoritative,
+    /// Read the record's current value back through the provider's API
+    Provider,
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Comparison::Dns => write!(f, "dns"),
+            Comparison::Authoritative => write!(f, "authoritative"),
+            Comparison::Provider => write!(f, "provider"),
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<Comparison, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "dns" => Ok(Comparison::Dns),
+            "authoritative" => Ok(Comparison::Authoritative),
+            "provider" => Ok(Comparison::Provider),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum Protocol {
     Cloudflare,
@@ -105,19 +183,43 @@ impl Protocol {
         }
     }
 
-    fn from_server(server: &str) -> Self {
-        match server {
-            "domains.google.com" => {
-                eprintln!("ERROR: Google Domains DDNS (domains.google.com) is no longer supported. Google sold Domains to Squarespace, which dropped DDNS support. Please migrate to Cloudflare: update your config to use 'server: cloudflare' with an 'api_token'. See README for migration steps.");
-                std::process::exit(1);
-            }
-            "cloudflare" => Self::Cloudflare,
-            "namecheap" => Self::Namecheap,
-            _ => Self::MailInABox,
+    /// The provider label used in metrics.
+    fn name(&self) -> &'static str {
+        match self {
+            Protocol::Cloudflare => "cloudflare",
+            Protocol::Namecheap => "namecheap",
+            Protocol::MailInABox => "mailinabox",
         }
     }
 }
 
+/// A DNS record as stored at Cloudflare
+struct CloudflareRecord {
+    zone_id: String,
+    id: String,
+    content: Option<IpAddr>,
+    ttl: Option<u32>,
+}
+
+/// Whether the Cloudflare record read back before an update already holds `ip` with the
+/// configured `ttl`, so the update can be skipped. Only `Comparison::Provider` trusts the
+/// read-back: with the other comparisons the state file or DNS already decided the record
+/// needs updating, and it is written.
+fn already_current(comparison: &Comparison, current: &CloudflareRecord, ip: IpAddr, ttl: Option<u32>) -> bool {
+    *comparison == Comparison::Provider
+        && current.content == Some(ip)
+        && ttl.is_none_or(|ttl| current.ttl == Some(ttl))
+}
+
+/// Whether a record last `published` has gone `max_age` seconds without an update.
+fn refresh_due(max_age: Option<u64>, published: Option<&state::Published>) -> bool {
+    match (max_age, published) {
+        (None, _) => false,
+        (Some(max_age), Some(published)) => published.age_secs() >= max_age,
+        (Some(_), None) => true,
+    }
+}
+
 #[derive(Debug)]
 pub struct APIClient {
     domain: String,
@@ -127,175 +229,478 @@ pub struct APIClient {
     server: String,
     protocol: Protocol,
     api_token: Option<String>,
+    /// Cloudflare record TTL, 1 being automatic; `None` leaves the record's TTL alone
+    ttl: Option<u32>,
     checker: Rc<ip_checker::IP>,
+    resolver: Resolver,
+    comparison: Comparison,
+    state: Rc<RefCell<State>>,
+    max_age: Option<u64>,
+    /// Zone nameservers found by `comparison_resolver`, kept between daemon cycles
+    authoritative: RefCell<Option<Resolver>>,
+    /// Outcome of the latest checks, by record type
+    status: RefCell<BTreeMap<String, RecordStatus>>,
+    http: reqwest::Client,
+    http_v4: reqwest::Client,
+    http_v6: reqwest::Client,
     logger: Logger,
 }
 
 impl APIClient {
-    fn new(
-        server: &str,
-        domain: &str,
-        methods: Vec<&str>,
-        records: Vec<&str>,
-        credentials: Credentials,
-        api_token: Option<String>,
-        checker: Rc<ip_checker::IP>,
-        ) -> APIClient {
-        let logger = Logger::new();
-
-        let methods: Vec<Method> = methods
-            .iter()
-            .map(|x| match Method::from_str(x) {
-                Ok(m) => m,
-                Err(_) => {
-                    logger.error(&format!(
-                        "Could not parse methods in config file; must be PUT, POST or DELETE (got '{}')",
-                        x
-                    ));
-                    process::exit(1);
-                }
-            })
-            .collect();
-
-        let records: Vec<Record> = records
-            .iter()
-            .map(|x| match Record::from_str(x) {
-                Ok(r) => r,
-                Err(_) => {
-                    logger.error(&format!(
-                        "Could not parse records in config file; must be A or AAAA (got '{}')",
-                        x
-                    ));
-                    process::exit(1);
-                }
-            })
-            .collect();
-
-        let protocol = Protocol::from_server(server);
+    /// Builds the client for a validated config `entry`, sharing `checker` and `state`
+    /// with the other entries.
+    fn from_entry(entry: Entry, checker: Rc<ip_checker::IP>, state: Rc<RefCell<State>>) -> APIClient {
+        let server = entry.provider.server().to_string();
+        let (protocol, credentials, api_token, ttl, methods) = match entry.provider {
+            Provider::Cloudflare { api_token, ttl } => (
+                Protocol::Cloudflare,
+                Credentials::new(String::new(), String::new()),
+                Some(api_token),
+                ttl,
+                // Cloudflare and Namecheap don't use methods, use a placeholder
+                vec![Method::Put],
+            ),
+            Provider::Namecheap { password } => (
+                Protocol::Namecheap,
+                Credentials::new(String::new(), password),
+                None,
+                None,
+                vec![Method::Put],
+            ),
+            Provider::MailInABox { username, password, methods, .. } => (
+                Protocol::MailInABox,
+                Credentials::new(username, password),
+                None,
+                None,
+                methods,
+            ),
+        };
 
-        Self {
-            domain: domain.to_string(),
-            server: server.to_string(),
+        let mut client = Self {
+            domain: entry.domain,
+            server,
             methods,
-            records,
+            records: entry.records,
             credentials,
             protocol,
             api_token,
+            ttl,
             checker,
-            logger,
+            resolver: Resolver::system(),
+            comparison: Comparison::Dns,
+            state,
+            max_age: None,
+            authoritative: RefCell::new(None),
+            status: RefCell::new(BTreeMap::new()),
+            http: reqwest::Client::builder()
+                .timeout(HTTP_TIMEOUT)
+                .build()
+                .expect("Unable to build HTTP client"),
+            http_v4: bound_client(Family::V4),
+            http_v6: bound_client(Family::V6),
+            logger: Logger::new(),
+        };
+        if !entry.nameservers.is_empty() {
+            client = client.with_resolver(Resolver::new(entry.nameservers));
+        }
+        if let Some(comparison) = entry.comparison {
+            client = client.with_comparison(comparison);
+        }
+        if let Some(max_age) = entry.max_age {
+            client = client.with_max_age(max_age);
+        }
+        client
+    }
+
+    /// Uses `resolver` instead of the system resolver to look up the domain's current records.
+    fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Sets how the entry decides whether its records need updating.
+    fn with_comparison(mut self, comparison: Comparison) -> Self {
+        self.comparison = comparison;
+        self
+    }
+
+    /// Forces an update once the last one is `max_age` seconds old, even if the address
+    /// did not change.
+    fn with_max_age(mut self, max_age: u64) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Whether `record` has gone `max_age` without an update. Without a recorded update it
+    /// is always due, so the timestamp gets established.
+    fn refresh_due(&self, record: &Record) -> bool {
+        refresh_due(
+            self.max_age,
+            self.state.borrow().get(&self.server, &self.domain, &record.to_string()),
+        )
+    }
+
+    /// Returns the resolver to compare the domain's records with: the zone's authoritative
+    /// nameservers for `Comparison::Authoritative`, otherwise the configured resolver.
+    async fn comparison_resolver(&self) -> Resolver {
+        if self.comparison != Comparison::Authoritative {
+            return self.resolver.clone();
+        }
+        if let Some(resolver) = self.authoritative.borrow().as_ref() {
+            return resolver.clone();
+        }
+        match self.resolver.authoritative_for(&self.domain).await {
+            Ok(resolver) => {
+                self.authoritative.replace(Some(resolver.clone()));
+                resolver
+            }
+            Err(err) => {
+                self.logger.warning(&format!(
+                    "{} could not find authoritative nameservers ({}); using the recursive resolver",
+                    self.domain, err
+                ));
+                self.resolver.clone()
+            }
         }
     }
 
+    /// Decides whether `record` needs publishing.
+    ///
+    /// The state file is the primary signal: an address different from the one last
+    /// published is an update. Once that publish is older than the propagation window, DNS
+    /// is checked as well, so a record changed elsewhere is put back. Without a state
+    /// entry, DNS decides alone. An entry with `max_age` is also updated once the last
+    /// update is that old.
+    async fn needs_update(&self, record: &Record, resolver: &Resolver) -> Result<bool, crate::error::DynamicError> {
+        let family = record.family();
+        if self.checker.actual_ip(family).is_some() && self.refresh_due(record) {
+            self.logger.info(&format!(
+                "{} {} has not been updated within max_age ({}s); refreshing",
+                self.domain,
+                record,
+                self.max_age.unwrap_or_default()
+            ));
+            return Ok(true);
+        }
+        let changed = self.address_changed(record, resolver).await?;
+        if changed {
+            metrics::ip_change(&self.domain, &record.to_string());
+        }
+        Ok(changed)
+    }
+
+    /// The part of `needs_update` that compares the actual address with the published one.
+    async fn address_changed(&self, record: &Record, resolver: &Resolver) -> Result<bool, crate::error::DynamicError> {
+        let family = record.family();
+        let published = self
+            .state
+            .borrow()
+            .get(&self.server, &self.domain, &record.to_string())
+            .cloned();
+        let (published, actual) = match (published, self.checker.actual_ip(family)) {
+            (Some(published), Some(actual)) => (published, actual),
+            _ => return self.compare(record, resolver).await,
+        };
+        match state_check(&published, actual) {
+            StateCheck::Changed => {
+                self.logger.info(&format!(
+                    "{} {} address changed: New IP: {} (last published {})",
+                    self.domain, record, actual, published.ip
+                ));
+                return Ok(true);
+            }
+            StateCheck::Settling => {
+                self.logger.debug(&format!(
+                    "{} {} {} was published {}s ago; not checking DNS",
+                    self.domain,
+                    record,
+                    actual,
+                    published.age_secs()
+                ));
+                return Ok(false);
+            }
+            StateCheck::CrossCheck => {}
+        }
+        match self.compare(record, resolver).await {
+            Ok(true) => {
+                self.logger.warning(&format!(
+                    "{} {} DNS does not match the last published address {}; publishing again",
+                    self.domain, record, actual
+                ));
+                Ok(true)
+            }
+            Ok(false) => Ok(false),
+            Err(err) => {
+                self.logger.warning(&format!(
+                    "{} {} DNS cross-check failed ({}); keeping the last published address {}",
+                    self.domain, record, err, actual
+                ));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Compares the detected address with the record's current value, looked up in DNS or,
+    /// for `Comparison::Provider`, read back through the provider's API.
+    async fn compare(&self, record: &Record, resolver: &Resolver) -> Result<bool, crate::error::DynamicError> {
+        if self.comparison != Comparison::Provider {
+            return self.checker.compare(&self.domain, record.family(), resolver).await;
+        }
+        let actual = match self.checker.actual_ip(record.family()) {
+            Some(ip) => ip,
+            None => return Err(format!("Could not determine actual {} address", record.family()).into()),
+        };
+        let current = match self.protocol {
+            Protocol::Cloudflare => {
+                let token = self.cloudflare_token()?;
+                self.cloudflare_record(token, record)
+                    .await?
+                    .content
+                    .into_iter()
+                    .collect()
+            }
+            Protocol::MailInABox => self.mailinabox_values(record).await?,
+            Protocol::Namecheap => return Err("Namecheap records cannot be read back".into()),
+        };
+        if current.contains(&actual) {
+            self.logger.debug(&format!(
+                "{} {} address did not change (provider has {:?})",
+                self.domain, record, current
+            ));
+            return Ok(false);
+        }
+        self.logger.info(&format!(
+            "{} {} address changed: New IP: {} (provider has {:?})",
+            self.domain, record, actual, current
+        ));
+        Ok(true)
+    }
+
+    /// Records in the state file that `ip` was published for `record`.
+    async fn remember(&self, record: &Record, ip: IpAddr) {
+        self.state
+            .borrow_mut()
+            .publish(&self.server, &self.domain, &record.to_string(), ip);
+        self.set_status(record, RecordStatus::succeeded);
+        state::save(&self.state).await;
+    }
+
+    fn set_status(&self, record: &Record, update: impl FnOnce(&mut RecordStatus)) {
+        update(self.status.borrow_mut().entry(record.to_string()).or_default());
+    }
+
+    /// Takes over the check history of the same server and domain from `previous`, so a
+    /// config reload does not reset how long a record has been failing.
+    pub fn inherit_status(&self, previous: &[APIClient]) {
+        if let Some(old) = previous
+            .iter()
+            .find(|old| old.server == self.server && old.domain == self.domain)
+        {
+            self.status.replace(old.status.borrow().clone());
+        }
+    }
+
+    /// Reports the detected and published address and the latest check of each record.
+    pub fn report(&self) -> Vec<RecordReport> {
+        self.records
+            .iter()
+            .map(|record| {
+                let published = self
+                    .state
+                    .borrow()
+                    .get(&self.server, &self.domain, &record.to_string())
+                    .cloned();
+                RecordReport {
+                    server: self.server.clone(),
+                    domain: self.domain.clone(),
+                    record: record.to_string(),
+                    detected: self.checker.actual_ip(record.family()),
+                    published: published.as_ref().map(|p| p.ip),
+                    last_update: published.map(|p| p.updated_at),
+                    status: self
+                        .status
+                        .borrow()
+                        .get(&record.to_string())
+                        .cloned()
+                        .unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
     pub async fn execute(&self) -> Result<(), crate::error::DynamicError> {
-        let changed = self.checker.compare(&self.domain).await?;
-        if !changed {
+        let resolver = self.comparison_resolver().await;
+        let mut changed = Vec::new();
+        for record in &self.records {
+            self.set_status(record, RecordStatus::checked);
+            match self.needs_update(record, &resolver).await {
+                Ok(true) => changed.push(record),
+                Ok(false) => {
+                    self.set_status(record, RecordStatus::succeeded);
+                    metrics::last_success(&self.domain, &record.to_string());
+                }
+                Err(err) => {
+                    self.set_status(record, |status| status.failed(&err.to_string()));
+                    // A missing IPv6 address should not stop the A record from updating
+                    if self.records.len() > 1 {
+                        self.logger.error(&format!("{} {} {}", self.domain, record, err));
+                    } else {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        if changed.is_empty() {
             return Ok(());
         }
 
-        if self.protocol == Protocol::Cloudflare {
-            return self.execute_cloudflare().await;
+        let started = Instant::now();
+        let result = match self.protocol {
+            Protocol::Cloudflare => self.execute_cloudflare(&changed).await,
+            Protocol::Namecheap => self.execute_namecheap(&changed).await,
+            Protocol::MailInABox => self.execute_mailinabox(&changed).await,
+        };
+        metrics::update_duration(self.protocol.name(), started.elapsed());
+        if let Err(err) = &result {
+            // The records the provider call failed on or did not get to
+            for record in &changed {
+                self.set_status(record, |status| {
+                    if !status.succeeded_since_check() {
+                        status.failed(&err.to_string());
+                    }
+                });
+            }
         }
-
-        if self.protocol == Protocol::Namecheap {
-            return self.execute_namecheap().await;
+        for record in &changed {
+            let succeeded = self.status.borrow()[&record.to_string()].succeeded_since_check();
+            metrics::update(self.protocol.name(), &self.domain, &record.to_string(), succeeded);
         }
+        result
+    }
+
+    async fn execute_mailinabox(&self, records: &[&Record]) -> Result<(), crate::error::DynamicError> {
         let mut calls = Vec::new();
-        for record in &mut self.records.iter() {
+        for record in records {
             let request_url =
                 self.protocol
                     .build_url(&self.server, &self.domain, &record.to_string());
-            let mut headers = header::HeaderMap::new();
-            headers.insert(
-                header::USER_AGENT,
-                header::HeaderValue::from_static("Rust Reqwest"),
-            );
-            headers.insert(
-                header::CONTENT_LENGTH,
-                header::HeaderValue::from_static("0"),
-            );
-            let client_builder = reqwest::Client::builder().default_headers(headers);
-            let client = match record {
-                Record::A => client_builder
-                    .local_address(IpAddr::from_str("0.0.0.0")?)
-                    .build()?,
-                Record::Aaaa => client_builder
-                    .local_address(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)))
-                    .build()?,
-            };
-            calls.push(self.call_all_methods(client, request_url, record))
+            calls.push(self.call_all_methods(request_url, record))
+        }
+        let results = future::join_all(calls).await;
+        for (record, result) in records.iter().zip(results) {
+            match (result, self.checker.actual_ip(record.family())) {
+                (Ok(true), Some(ip)) if self.publishes() => self.remember(record, ip).await,
+                (Ok(true), _) => self.set_status(record, RecordStatus::succeeded),
+                (Ok(false), _) => {
+                    self.set_status(record, |status| status.failed("server returned an error status"));
+                }
+                (Err(err), _) => {
+                    self.logger.error(&format!("{} {} {}", self.domain, record, err));
+                    self.set_status(record, |status| status.failed(&err.to_string()));
+                }
+            }
         }
-        future::join_all(calls).await;
         Ok(())
     }
 
-    async fn execute_cloudflare(&self) -> Result<(), crate::error::DynamicError> {
-        let token = match &self.api_token {
-            Some(t) => t.clone(),
-            None => {
-                return Err("Cloudflare api_token is required".into());
-            }
-        };
+    /// Whether the configured methods set the record's value, rather than only deleting it.
+    fn publishes(&self) -> bool {
+        self.methods
+            .iter()
+            .any(|method| matches!(method, Method::Post | Method::Put))
+    }
 
-        let ip = match self.checker.actual_ip() {
-            Some(ip) => ip,
-            None => {
-                return Err("Could not determine actual IP".into());
-            }
-        };
+    fn cloudflare_token(&self) -> Result<&str, crate::error::DynamicError> {
+        match &self.api_token {
+            Some(t) => Ok(t),
+            None => Err("Cloudflare api_token is required".into()),
+        }
+    }
 
+    /// Looks up the zone and the `record` entry for the domain at Cloudflare.
+    async fn cloudflare_record(
+        &self,
+        token: &str,
+        record: &Record,
+    ) -> Result<CloudflareRecord, crate::error::DynamicError> {
+        let record_type = record.to_string();
         let apex_domain = apex_domain_from(&self.domain);
+        let client = &self.http;
 
-        let client = reqwest::Client::new();
+        // Resolve zone ID
+        let zone_url = format!(
+            "https://api.cloudflare.com/client/v4/zones?name={}",
+            apex_domain
+        );
+        let zone_resp = client
+            .get(&zone_url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
 
-        for record in &self.records {
-            let record_type = record.to_string();
+        let zone_id = zone_resp["result"][0]["id"]
+            .as_str()
+            .ok_or_else(|| format!("Could not find Cloudflare zone for domain '{}'", apex_domain))?
+            .to_string();
 
-            // Resolve zone ID
-            let zone_url = format!(
-                "https://api.cloudflare.com/client/v4/zones?name={}",
-                apex_domain
-            );
-            let zone_resp = client
-                .get(&zone_url)
-                .header("Authorization", format!("Bearer {}", token))
-                .send()
-                .await?
-                .json::<serde_json::Value>()
-                .await?;
+        // Resolve record ID
+        let records_url = format!(
+            "https://api.cloudflare.com/client/v4/zones/{}/dns_records?name={}&type={}",
+            zone_id, self.domain, record_type
+        );
+        let records_resp = client
+            .get(&records_url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
 
-            let zone_id = zone_resp["result"][0]["id"]
-                .as_str()
-                .ok_or_else(|| format!("Could not find Cloudflare zone for domain '{}'", apex_domain))?
-                .to_string();
+        let current = &records_resp["result"][0];
+        let id = current["id"]
+            .as_str()
+            .ok_or_else(|| format!("Could not find Cloudflare DNS record for '{}' type {}", self.domain, record_type))?
+            .to_string();
+        let content = current["content"].as_str().and_then(|c| IpAddr::from_str(c).ok());
+        let ttl = current["ttl"].as_u64().and_then(|ttl| u32::try_from(ttl).ok());
+        Ok(CloudflareRecord { zone_id, id, content, ttl })
+    }
 
-            // Resolve record ID
-            let records_url = format!(
-                "https://api.cloudflare.com/client/v4/zones/{}/dns_records?name={}&type={}",
-                zone_id, self.domain, record_type
-            );
-            let records_resp = client
-                .get(&records_url)
-                .header("Authorization", format!("Bearer {}", token))
-                .send()
-                .await?
-                .json::<serde_json::Value>()
-                .await?;
+    async fn execute_cloudflare(&self, records: &[&Record]) -> Result<(), crate::error::DynamicError> {
+        let token = self.cloudflare_token()?;
+        let client = &self.http;
 
-            let record_id = records_resp["result"][0]["id"]
-                .as_str()
-                .ok_or_else(|| format!("Could not find Cloudflare DNS record for '{}' type {}", self.domain, record_type))?
-                .to_string();
+        for record in records {
+            let record_type = record.to_string();
+            let ip = match self.checker.actual_ip(record.family()) {
+                Some(ip) => ip,
+                None => {
+                    return Err(format!("Could not determine actual {} address", record.family()).into());
+                }
+            };
+
+            let current = self.cloudflare_record(token, record).await?;
+            if already_current(&self.comparison, &current, ip, self.ttl) && !self.refresh_due(record) {
+                self.logger.info(&format!(
+                    "{} {} Cloudflare already has {}",
+                    self.domain, record_type, ip
+                ));
+                self.remember(record, ip).await;
+                continue;
+            }
 
             // Update record
             let update_url = format!(
                 "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
-                zone_id, record_id
+                current.zone_id, current.id
             );
             let body = serde_json::json!({
                 "type": record_type,
                 "name": self.domain,
                 "content": ip.to_string(),
-                "ttl": 1
+                "ttl": self.ttl.or(current.ttl).unwrap_or(1)
             });
 
             let update_resp = client
@@ -312,6 +717,7 @@ impl APIClient {
                     "{} {} Cloudflare updated to {}",
                     self.domain, record_type, ip
                 ));
+                self.remember(record, ip).await;
             } else {
                 let errors = update_resp["errors"].to_string();
                 self.logger.error(&format!(
@@ -325,8 +731,8 @@ impl APIClient {
         Ok(())
     }
 
-    async fn execute_namecheap(&self) -> Result<(), crate::error::DynamicError> {
-        for record in &self.records {
+    async fn execute_namecheap(&self, records: &[&Record]) -> Result<(), crate::error::DynamicError> {
+        for record in records {
             if let Record::Aaaa = record {
                 let msg = "Namecheap DDNS does not support AAAA records";
                 self.logger.error(msg);
@@ -334,9 +740,9 @@ impl APIClient {
             }
         }
 
-        let ip = match self.checker.actual_ip() {
+        let ip = match self.checker.actual_ip(Family::V4) {
             Some(ip) => ip,
-            None => return Err("Could not determine actual IP".into()),
+            None => return Err("Could not determine actual IPv4 address".into()),
         };
 
         let parts: Vec<&str> = self.domain.splitn(2, '.').collect();
@@ -347,8 +753,8 @@ impl APIClient {
         };
 
         let password = &self.credentials.password;
-        let client = reqwest::Client::new();
-        let resp = client
+        let resp = self
+            .http
             .get("https://dynamicdns.park-your-domain.com/update")
             .query(&[
                 ("host", host.as_str()),
@@ -366,6 +772,7 @@ impl APIClient {
                 "{} A Namecheap updated to {}",
                 self.domain, ip
             ));
+            self.remember(&Record::A, ip).await;
         } else {
             let err_text = extract_xml_tag(&resp, "Err1")
                 .unwrap_or_else(|| resp.clone());
@@ -379,45 +786,191 @@ impl APIClient {
         Ok(())
     }
 
-    async fn call_all_methods(&self, client: reqwest::Client, url: String, record: &Record) -> Result<(), reqwest::Error> {
+    /// The Mail-in-a-Box client for `family`, bound so requests go out over that family.
+    fn family_client(&self, family: Family) -> &reqwest::Client {
+        match family {
+            Family::V4 => &self.http_v4,
+            Family::V6 => &self.http_v6,
+        }
+    }
+
+    /// Reads the `record` values for the domain from the Mail-in-a-Box custom DNS API.
+    async fn mailinabox_values(&self, record: &Record) -> Result<Vec<IpAddr>, crate::error::DynamicError> {
+        let url = self
+            .protocol
+            .build_url(&self.server, &self.domain, &record.to_string());
+        let client = self
+            .credentials
+            .authenticate(self.family_client(record.family()).get(&url));
+        let resp = client
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+        let values = resp
+            .as_array()
+            .ok_or_else(|| format!("unexpected response from {}: {}", self.server, resp))?;
+        Ok(values
+            .iter()
+            .filter_map(|v| v["value"].as_str())
+            .filter_map(|v| IpAddr::from_str(v).ok())
+            .collect())
+    }
+
+    /// Sends each configured method in turn and returns whether every request got a
+    /// success status.
+    async fn call_all_methods(&self, url: String, record: &Record) -> Result<bool, reqwest::Error> {
+        let client = self.family_client(record.family());
+        let mut succeeded = true;
         for method in &self.methods {
-            match method {
-                Method::Post => {
-                    let client = client.post(&url);
-                    self.manage_request(client, method, record).await?;
-                }
-                Method::Delete => {
-                    let client = client.delete(&url);
-                    self.manage_request(client, method, record).await?;
-                }
-                Method::Put => {
-                    let client = client.put(&url);
-                    self.manage_request(client, method, record).await?;
-                }
+            let request = match method {
+                Method::Post => client.post(&url),
+                Method::Delete => client.delete(&url),
+                Method::Put => client.put(&url),
             };
+            succeeded &= self.manage_request(request, method, record).await?;
         }
-        Ok(())
+        Ok(succeeded)
     }
 
+    /// Sends one request and returns whether the server answered with a success status.
     async fn manage_request(
         &self,
         client: RequestBuilder,
         method: &Method,
         record: &Record,
-    ) -> Result<(), reqwest::Error> {
+    ) -> Result<bool, reqwest::Error> {
+        // Mail-in-a-Box takes the address the request comes from; the client of the
+        // record's family makes that an address of the right family
+        let client = client.header(header::CONTENT_LENGTH, header::HeaderValue::from_static("0"));
         let client = self.credentials.authenticate(client);
         let resp = client.send().await?;
+        let success = resp.status().is_success();
         let text = resp.text().await?;
         self.logger
             .info(&format!("{} {} {} {}", &self.domain, record, method, text));
-        Ok(())
+        Ok(success)
+    }
+
+    /// Loads every entry of the config file and determines the actual addresses they need,
+    /// exiting if the file cannot be read or is invalid. Without a `format`, the file's
+    /// extension decides it.
+    pub async fn from_config_file(
+        filename: String,
+        format: Option<ConfigFormat>,
+        skip_invalid: bool,
+    ) -> (Vec<APIClient>, Vec<String>) {
+        match APIClient::try_from_config_file(&filename, format, skip_invalid).await {
+            Ok(config) => config,
+            Err(err) => {
+                Logger::new().error(&err);
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Like `from_config_file`, but returns an error instead of exiting. The whole file is
+    /// validated before any address is looked up. Besides the clients, returns the paths of
+    /// the files and config directories that were read, to watch for changes.
+    ///
+    /// Every problem in the file is reported together. With `skip_invalid`, the invalid
+    /// entries are logged and left out, as long as at least one valid entry remains.
+    pub async fn try_from_config_file(
+        filename: &str,
+        format: Option<ConfigFormat>,
+        skip_invalid: bool,
+    ) -> Result<(Vec<APIClient>, Vec<String>), String> {
+        // `cmd:` secrets may take a while, and must not hold up the event loop meanwhile
+        let path = filename.to_string();
+        let mut loaded = tokio::task::spawn_blocking(move || config::load(&path, format, true))
+            .await
+            .map_err(|err| format!("Could not load {}: {}", filename, err))?
+            .map_err(|err| err.to_string())?;
+        if let Some(skipped) = loaded.accept(skip_invalid)? {
+            Logger::new().warning(&skipped);
+        }
+        for warning in &loaded.warnings {
+            Logger::new().warning(&warning.to_string());
+        }
+        let checkers = build_checkers(&loaded.entries);
+        let state = Rc::new(RefCell::new(State::load(state::get_state_file_path())));
+        let config: Vec<APIClient> = loaded
+            .entries
+            .drain(..)
+            .zip(checkers)
+            .map(|(entry, checker)| APIClient::from_entry(entry, checker, Rc::clone(&state)))
+            .collect();
+        refresh_checkers(&config).await;
+        let sources = loaded.files.into_iter().chain(loaded.directories).collect();
+        Ok((config, sources))
+    }
+}
+
+/// Builds an HTTP client whose connections are bound to the unspecified address of `family`.
+fn bound_client(family: Family) -> reqwest::Client {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(
+        header::USER_AGENT,
+        header::HeaderValue::from_static("Rust Reqwest"),
+    );
+    let local_address = match family {
+        Family::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        Family::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .local_address(local_address)
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("Unable to build HTTP client")
+}
+
+/// Determines the actual addresses (again) for every IP checker used by `clients`, once
+/// per checker, for the families its clients' records need.
+///
+/// A source that fails to determine any address is logged; entries using it will fail
+/// when they compare, while entries using other sources still run.
+pub async fn refresh_checkers(clients: &[APIClient]) {
+    let mut checkers: Vec<(&Rc<ip_checker::IP>, Vec<Family>)> = Vec::new();
+    for client in clients {
+        let index = match checkers.iter().position(|(c, _)| Rc::ptr_eq(c, &client.checker)) {
+            Some(index) => index,
+            None => {
+                checkers.push((&client.checker, Vec::new()));
+                checkers.len() - 1
+            }
+        };
+        for record in &client.records {
+            if !checkers[index].1.contains(&record.family()) {
+                checkers[index].1.push(record.family());
+            }
+        }
     }
+    // Failures are already logged by `refresh`
+    future::join_all(
+        checkers
+            .iter()
+            .map(|(checker, families)| checker.refresh(families)),
+    )
+    .await;
+}
 
-    pub async fn from_config_file(filename: String) -> Vec<APIClient> {
-        let yaml = load_yaml_from_file(&filename);
-        let yaml = yaml.clone();
-        parse_yaml(yaml, filename).await
+/// The interfaces whose address changes matter to `clients`: the named interfaces if every
+/// entry uses an interface IP source, otherwise all of them (`None`).
+pub fn watched_interfaces(clients: &[APIClient]) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+    for client in clients {
+        match client.checker.source() {
+            IpSource::Interface(source) => {
+                if !names.contains(&source.name) {
+                    names.push(source.name.clone());
+                }
+            }
+            _ => return None,
+        }
     }
+    Some(names)
 }
 
 fn apex_domain_from(domain: &str) -> String {
@@ -437,161 +990,22 @@ fn extract_xml_tag(xml: &str, tag: &str) -> Option<String> {
     Some(xml[start..start + end].to_string())
 }
 
-fn resolve_secret(value: &str) -> Result<String, crate::error::DynamicError> {
-    if let Some(var_name) = value.strip_prefix("env:") {
-        match std::env::var(var_name) {
-            Ok(v) if !v.is_empty() => Ok(v),
-            _ => Err(format!("password env var '{}' is not set", var_name).into()),
-        }
-    } else {
-        Ok(value.to_string())
-    }
-}
-
-fn load_yaml_from_file(file: &str) -> Vec<Yaml> {
-    let logger = crate::logging::Logger::new();
-    let mut handle = match File::open(file) {
-        Ok(f) => f,
-        Err(_) => {
-            logger.error(&format!("Could not load config file {}", file));
-            std::process::exit(1)
-        }
-    };
-    let mut contents = String::new();
-
-    match handle.read_to_string(&mut contents) {
-        Ok(_) => (),
-        Err(err) => {
-            logger.debug(&format!("{:#?}", err));
-            std::process::exit(1)
-        }
-    }
-
-    YamlLoader::load_from_str(&contents).expect("Unable to parse YAML")
-}
-
-async fn parse_yaml(docs: Vec<Yaml>, file: String) -> Vec<APIClient> {
-    let mut checker = crate::ip_checker::IP::new();
-    match checker.set_actual().await {
-        Ok(_) => {}
-        Err(err) => {
-            let logger = Logger::new();
-            logger.error(&format!("{}", err));
-            process::exit(1)
-        }
-    }
-    let checker = Rc::new(checker);
-    let logger = Logger::new();
+/// Builds one IP checker per distinct `ip_source`, shared by every entry that uses it.
+///
+/// No addresses are determined yet; see `refresh_checkers`.
+fn build_checkers(entries: &[Entry]) -> Vec<Rc<ip_checker::IP>> {
+    let mut checkers: Vec<Rc<ip_checker::IP>> = Vec::new();
     let mut config = Vec::new();
-    for doc in docs.iter() {
-        let server = match doc["server"].as_str() {
-            Some(result) => result,
+    for entry in entries {
+        let checker = match checkers.iter().find(|checker| *checker.source() == entry.ip_source) {
+            Some(checker) => Rc::clone(checker),
             None => {
-                logger.error(&format!("'server' should be in {}", file));
-                process::exit(1);
+                let checker = Rc::new(ip_checker::IP::new(entry.ip_source.clone()));
+                checkers.push(Rc::clone(&checker));
+                checker
             }
         };
-        let domain = match doc["domain"].as_str() {
-            Some(result) => result,
-            None => {
-                logger.error(&format!("'domain' should be in {}", file));
-                process::exit(1);
-            }
-        };
-
-        let (credentials, api_token) = if server == "cloudflare" {
-            let raw_token = match doc["api_token"].as_str() {
-                Some(t) => t,
-                None => {
-                    logger.error(&format!("'api_token' is required for Cloudflare in {}", file));
-                    process::exit(1);
-                }
-            };
-            let token = match resolve_secret(raw_token) {
-                Ok(v) => v,
-                Err(e) => { logger.error(&format!("{}", e)); process::exit(1); }
-            };
-            (Credentials::new(String::new(), String::new()), Some(token))
-        } else if server == "namecheap" {
-            let raw_password = match doc["password"].as_str() {
-                Some(result) => result,
-                None => {
-                    logger.error(&format!("'password' is required for Namecheap in {}", file));
-                    process::exit(1);
-                }
-            };
-            let password = match resolve_secret(raw_password) {
-                Ok(v) => v,
-                Err(e) => { logger.error(&format!("{}", e)); process::exit(1); }
-            };
-            (Credentials::new(String::new(), password), None)
-        } else {
-            let username = match doc["username"].as_str() {
-                Some(result) => result,
-                None => {
-                    logger.error(&format!("'username' should be in {}", file));
-                    process::exit(1);
-                }
-            };
-            let password = match doc["password"].as_str() {
-                Some(result) => result,
-                None => {
-                    logger.error(&format!("'password' should be in {}", file));
-                    process::exit(1);
-                }
-            };
-            let username = match resolve_secret(username) {
-                Ok(v) => v,
-                Err(e) => { logger.error(&format!("{}", e)); process::exit(1); }
-            };
-            let password = match resolve_secret(password) {
-                Ok(v) => v,
-                Err(e) => { logger.error(&format!("{}", e)); process::exit(1); }
-            };
-            (Credentials::new(username, password), None)
-        };
-
-        let methods: Vec<&str> = if server == "cloudflare" || server == "namecheap" {
-            // Cloudflare and Namecheap don't use methods, use a placeholder
-            vec!["put"]
-        } else {
-            match doc["methods"].as_vec() {
-                Some(methods_vec) => {
-                    methods_vec
-                        .iter()
-                        .map(|m| match m.as_str() {
-                            Some(method) => method,
-                            None => {
-                                logger.error(&format!("could not parse 'methods' list in {}", file));
-                                process::exit(1);
-                            }
-                        })
-                        .collect()
-                }
-                None => {
-                    logger.error(&format!("'methods' (list) should be in {}", file));
-                    process::exit(1);
-                }
-            }
-        };
-
-        let records = doc["records"].as_vec();
-        let records = match records {
-            Some(v) => v
-                    .iter()
-                    .map(|r| match r.as_str() {
-                        Some(record) => record,
-                        None => {
-                            logger.error(&format!("could not parse 'records' list in {}", file));
-                            process::exit(1);
-                        }
-                    })
-                    .collect(),
-            None => vec!["a"],
-        };
-        let checker_clone = Rc::clone(&checker);
-        let api = APIClient::new(server, domain, methods, records, credentials, api_token, checker_clone);
-        config.push(api)
+        config.push(checker);
     }
     config
 }
@@ -626,3 +1040,63 @@ fn build_config_path(path: &mut String) {
     }
     path.push_str(DEFAULT_CONFIG_FILE);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn published(ip: &str, age: u64) -> state::Published {
+        state::Published {
+            ip: ip.parse().unwrap(),
+            updated_at: state::now_secs() - age,
+        }
+    }
+
+    #[test]
+    fn checks_state_before_dns() {
+        let actual: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(state_check(&published("203.0.113.6", 10), actual), StateCheck::Changed);
+        assert_eq!(
+            state_check(&published("203.0.113.6", PROPAGATION_WINDOW_SECS * 2), actual),
+            StateCheck::Changed
+        );
+        assert_eq!(state_check(&published("203.0.113.7", 10), actual), StateCheck::Settling);
+        assert_eq!(
+            state_check(&published("203.0.113.7", PROPAGATION_WINDOW_SECS - 1), actual),
+            StateCheck::Settling
+        );
+        assert_eq!(
+            state_check(&published("203.0.113.7", PROPAGATION_WINDOW_SECS), actual),
+            StateCheck::CrossCheck
+        );
+    }
+
+    #[test]
+    fn refreshes_after_max_age() {
+        assert!(!refresh_due(None, Some(&published("203.0.113.7", 10_000))));
+        assert!(!refresh_due(Some(3600), Some(&published("203.0.113.7", 3599))));
+        assert!(refresh_due(Some(3600), Some(&published("203.0.113.7", 3600))));
+        // Without a recorded update the timestamp still has to be established
+        assert!(refresh_due(Some(3600), None));
+        assert!(!refresh_due(None, None));
+    }
+
+    #[test]
+    fn trusts_cloudflare_read_back_only_for_provider_comparison() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let current = |content: &str, ttl| CloudflareRecord {
+            zone_id: "zone".into(),
+            id: "record".into(),
+            content: content.parse().ok(),
+            ttl,
+        };
+        assert!(already_current(&Comparison::Provider, &current("203.0.113.7", Some(1)), ip, None));
+        assert!(already_current(&Comparison::Provider, &current("203.0.113.7", Some(300)), ip, Some(300)));
+        assert!(!already_current(&Comparison::Provider, &current("203.0.113.7", Some(1)), ip, Some(300)));
+        assert!(!already_current(&Comparison::Provider, &current("203.0.113.6", Some(1)), ip, None));
+        assert!(!already_current(&Comparison::Provider, &current("", None), ip, None));
+        for comparison in [Comparison::Dns, Comparison::Authoritative] {
+            assert!(!already_current(&comparison, &current("203.0.113.7", Some(1)), ip, None));
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use clap::{command, Parser, Subcommand};
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Parser)]
 #[command(author, version, long_about = "Dynamic DNS Client")]
@@ -7,10 +9,38 @@ pub struct Args {
     pub config_file: Option<String>,
     #[arg(short, long)]
     pub ip: bool,
+    /// Write Prometheus metrics to this file after each run, for node_exporter's textfile collector
+    #[arg(long, global = true)]
+    pub metrics_file: Option<PathBuf>,
+    /// What to do when another run is still using the same config file
+    #[arg(long, global = true, value_enum, default_value_t = IfLocked::Skip)]
+    pub if_locked: IfLocked,
+    /// Run the valid entries of a config file that also has invalid ones, instead of none
+    #[arg(long, global = true)]
+    pub skip_invalid: bool,
+    /// Format of the config file; by default .toml and .json files are read as such, and
+    /// anything else as YAML
+    #[arg(long, global = true, value_enum)]
+    pub config_format: Option<ConfigFormat>,
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum IfLocked {
+    /// Log why and exit without checking
+    Skip,
+    /// Wait for the other run to finish, then check
+    Wait,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     Install {
@@ -20,9 +50,39 @@ pub enum Commands {
         log_file: Option<String>,
         #[arg(long)]
         config_file: Option<String>,
+        /// Install a resident daemon service (Linux only) instead of a timer
+        #[arg(long, default_value_t = false)]
+        daemon: bool,
     },
     Uninstall {
         #[arg(long, default_value_t = false)]
         purge: bool,
     },
+    /// Work with the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Stay resident and re-check on an interval
+    Daemon {
+        #[arg(long, default_value = "5min")]
+        interval: String,
+        /// Random extra delay added to each interval, up to this long
+        #[arg(long, default_value = "30s")]
+        jitter: String,
+        /// Serve status JSON and /healthz on this address, e.g. 127.0.0.1:8053
+        #[arg(long)]
+        listen: Option<SocketAddr>,
+        /// How long a record may keep failing before /healthz reports unhealthy
+        #[arg(long, default_value = "30min")]
+        health_threshold: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommands {
+    /// Validate the config file without contacting any IP service or provider
+    Check,
+    /// Ask for a provider, domain and secret, and add an entry to the config file
+    Init,
 }
@@ -0,0 +1,2386 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{Display, Formatter},
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::{de::IgnoredAny, Deserialize};
+use yaml_rust::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::{Marker, ScanError, TScalarStyle, TokenType},
+    Yaml,
+};
+
+use crate::api_client::{Comparison, Method, Record};
+use crate::arg_parser::ConfigFormat;
+use crate::ip_checker::{
+    dns::{DnsProvider, DnsSource},
+    http::{Endpoint, HttpSource, ResponseFormat, Strategy},
+    interface::InterfaceSource,
+    router::{RouterProtocol, RouterSource},
+    stun::StunSource,
+    Family, IpSource,
+};
+use crate::time_tools;
+
+/// One document of the config file, as written
+///
+/// Every field is optional here so that all the problems of a document can be reported
+/// together; `validate` turns it into an `Entry`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EntryConfig {
+    pub server: Option<String>,
+    pub domain: Option<String>,
+    /// Several domains sharing the rest of the entry, each becoming an entry of its own
+    pub domains: Option<Vec<DomainConfig>>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub api_token: Option<String>,
+    pub methods: Option<Vec<String>>,
+    pub records: Option<Vec<String>>,
+    pub nameservers: Option<Vec<String>>,
+    pub ip_source: Option<IpSourceConfig>,
+    pub compare: Option<String>,
+    pub max_age: Option<DurationConfig>,
+    /// Cloudflare record TTL: `auto` or a duration
+    pub ttl: Option<DurationConfig>,
+    /// Other config files or directories to load, relative to this file's directory
+    pub include: Option<PathsConfig>,
+    /// Fields for the entries of the later documents of the same file
+    pub defaults: Option<Box<EntryConfig>>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, IgnoredAny>,
+}
+
+impl EntryConfig {
+    /// The names of the entry fields that are set, in declaration order.
+    fn fields_set(&self) -> Vec<&'static str> {
+        let fields = [
+            ("server", self.server.is_some()),
+            ("domain", self.domain.is_some()),
+            ("domains", self.domains.is_some()),
+            ("username", self.username.is_some()),
+            ("password", self.password.is_some()),
+            ("api_token", self.api_token.is_some()),
+            ("methods", self.methods.is_some()),
+            ("records", self.records.is_some()),
+            ("nameservers", self.nameservers.is_some()),
+            ("ip_source", self.ip_source.is_some()),
+            ("compare", self.compare.is_some()),
+            ("max_age", self.max_age.is_some()),
+            ("ttl", self.ttl.is_some()),
+        ];
+        fields.into_iter().filter(|(_, set)| *set).map(|(name, _)| name).collect()
+    }
+
+    /// Fills the fields this document leaves unset from a `defaults` document.
+    fn with_defaults(self, defaults: &EntryConfig) -> EntryConfig {
+        EntryConfig {
+            server: self.server.or_else(|| defaults.server.clone()),
+            domain: self.domain,
+            domains: self.domains,
+            username: self.username.or_else(|| defaults.username.clone()),
+            password: self.password.or_else(|| defaults.password.clone()),
+            api_token: self.api_token.or_else(|| defaults.api_token.clone()),
+            methods: self.methods.or_else(|| defaults.methods.clone()),
+            records: self.records.or_else(|| defaults.records.clone()),
+            nameservers: self.nameservers.or_else(|| defaults.nameservers.clone()),
+            ip_source: self.ip_source.or_else(|| defaults.ip_source.clone()),
+            compare: self.compare.or_else(|| defaults.compare.clone()),
+            max_age: self.max_age.or_else(|| defaults.max_age.clone()),
+            ttl: self.ttl.or_else(|| defaults.ttl.clone()),
+            include: self.include,
+            defaults: self.defaults,
+            unknown: self.unknown,
+        }
+    }
+}
+
+/// An item of `domains`: a bare domain, or a domain with its own records, TTL or IP source
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum DomainConfig {
+    Name(String),
+    Detailed(Box<HostConfig>),
+}
+
+/// The settings one item of `domains` may override
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HostConfig {
+    pub domain: Option<String>,
+    pub records: Option<Vec<String>>,
+    pub ttl: Option<DurationConfig>,
+    pub ip_source: Option<IpSourceConfig>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, IgnoredAny>,
+}
+
+/// The `ip_source` section of a document
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IpSourceConfig {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub name: Option<String>,
+    pub endpoints: Option<Vec<EndpointConfig>>,
+    pub strategy: Option<String>,
+    pub quorum: Option<i64>,
+    pub protocols: Option<Vec<String>>,
+    pub gateway: Option<String>,
+    pub ssdp_address: Option<String>,
+    pub provider: Option<String>,
+    pub resolvers: Option<Vec<String>>,
+    pub servers: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, IgnoredAny>,
+}
+
+/// An HTTP endpoint, either a bare URL or a map with the response format
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EndpointConfig {
+    Url(String),
+    Detailed {
+        url: Option<String>,
+        format: Option<String>,
+        field: Option<String>,
+        pattern: Option<String>,
+        family: Option<String>,
+    },
+}
+
+/// One path or a list of them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PathsConfig {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// A number of seconds or a duration string such as `30d`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum DurationConfig {
+    Seconds(i64),
+    Text(String),
+}
+
+/// A validated config entry: one domain at one provider
+#[derive(Debug)]
+pub struct Entry {
+    /// The file the entry came from
+    pub file: String,
+    /// 1-based index of the document the entry came from
+    pub document: usize,
+    pub domain: String,
+    pub provider: Provider,
+    pub records: Vec<Record>,
+    pub nameservers: Vec<SocketAddr>,
+    pub ip_source: IpSource,
+    pub comparison: Option<Comparison>,
+    pub max_age: Option<u64>,
+}
+
+/// The provider of an entry, with the credentials and options that provider needs;
+/// secrets are already resolved
+#[derive(Debug)]
+pub enum Provider {
+    Cloudflare {
+        api_token: String,
+        /// Record TTL in seconds, 1 being automatic; `None` leaves Cloudflare's default
+        ttl: Option<u32>,
+    },
+    Namecheap {
+        password: String,
+    },
+    MailInABox {
+        server: String,
+        username: String,
+        password: String,
+        methods: Vec<Method>,
+    },
+}
+
+impl Provider {
+    /// The `server` value the entry was configured with.
+    pub fn server(&self) -> &str {
+        match self {
+            Provider::Cloudflare { .. } => "cloudflare",
+            Provider::Namecheap { .. } => "namecheap",
+            Provider::MailInABox { server, .. } => server,
+        }
+    }
+}
+
+/// A problem with the config, located as precisely as possible
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub file: String,
+    /// 1-based index of the document
+    pub document: Option<usize>,
+    pub line: Option<usize>,
+    /// Path of the offending field, e.g. `ip_source.endpoints[1].format`
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        if let Some(document) = self.document {
+            write!(f, " (document {})", document)?;
+        }
+        match &self.field {
+            Some(field) => write!(f, ": {}: {}", field, self.message),
+            None => write!(f, ": {}", self.message),
+        }
+    }
+}
+
+/// The result of loading a config file: the valid entries and the problems of the rest
+#[derive(Debug, Default)]
+pub struct Config {
+    pub entries: Vec<Entry>,
+    pub errors: Vec<ConfigError>,
+    /// Settings that are valid but have no effect, e.g. `methods` for Cloudflare
+    pub warnings: Vec<ConfigError>,
+    /// `include` documents, with the paths they name
+    pub includes: Vec<Include>,
+    /// File and document number of each `defaults` document
+    pub defaults: Vec<(String, usize)>,
+    /// Number of entries left out because they are invalid
+    pub invalid: usize,
+    /// The files read, in the order they were loaded
+    pub files: Vec<String>,
+    /// The config directories whose files were loaded, the top one or an `include`
+    pub directories: Vec<String>,
+    /// Number of documents read, including empty ones
+    pub documents: usize,
+    /// The `defaults` document in effect while a file is parsed
+    current_defaults: Option<Defaults>,
+    secrets: Secrets,
+}
+
+/// Secrets resolved while loading, by their value as written, so that a secret shared by
+/// several entries or `domains` items is read, and its command run, once per load
+#[derive(Default)]
+struct Secrets {
+    /// Whether `cmd:` commands are run; otherwise they are only checked to name a command
+    run_commands: bool,
+    resolved: HashMap<String, Result<(String, Option<String>), String>>,
+}
+
+impl Secrets {
+    /// Resolves `value` like `resolve_secret`. A warning is only returned where the secret
+    /// is first used.
+    fn resolve(&mut self, value: &str) -> Result<(String, Option<String>), String> {
+        if !self.run_commands {
+            if let Some(command) = value.strip_prefix("cmd:") {
+                if command.trim().is_empty() {
+                    return Err("cmd: must be followed by a command".to_string());
+                }
+                return Ok((String::new(), None));
+            }
+        }
+        if let Some(resolved) = self.resolved.get(value) {
+            return resolved.clone().map(|(secret, _)| (secret, None));
+        }
+        let resolved = resolve_secret(value);
+        self.resolved.insert(value.to_string(), resolved.clone());
+        resolved
+    }
+}
+
+impl std::fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Secrets")
+            .field("run_commands", &self.run_commands)
+            .field("resolved", &self.resolved.len())
+            .finish()
+    }
+}
+
+/// A `defaults` document, applied to the entries after it in the same file
+#[derive(Debug)]
+struct Defaults {
+    raw: EntryConfig,
+    /// Lines of the default fields, by their path within `defaults`
+    spans: Option<DocumentSpans>,
+}
+
+/// One path of an `include` document
+#[derive(Debug, Clone)]
+pub struct Include {
+    pub file: String,
+    pub document: usize,
+    /// `include`, or `include[i]` for a list
+    pub field: String,
+    pub line: Option<usize>,
+    /// The path as written, relative to the directory of `file`
+    pub path: String,
+}
+
+/// File name extensions loaded from a config directory
+const CONFIG_EXTENSIONS: [&str; 5] = ["conf", "yaml", "yml", "toml", "json"];
+
+/// Whether a file named `name` is loaded from a config directory: it has one of
+/// `CONFIG_EXTENSIONS` and is not hidden, which leaves out editor backup and swap files.
+pub fn is_config_file_name(name: &str) -> bool {
+    !name.starts_with('.')
+        && name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| CONFIG_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Reads and validates the config at `path`: a file, or a directory whose config files
+/// are loaded in name order. Files named by `include` documents are loaded after the file
+/// that includes them. Each file is parsed as `format`, or else the format its extension
+/// implies.
+///
+/// Each distinct secret is resolved once. Without `run_commands`, `cmd:` secrets are only
+/// checked to name a command, and their entries get an empty secret.
+///
+/// Only failing to read `path` itself is an `Err`; problems with individual documents and
+/// included files are collected in `Config::errors`, each naming its file.
+pub fn load(path: &str, format: Option<ConfigFormat>, run_commands: bool) -> Result<Config, ConfigError> {
+    let mut loader = Loader {
+        format,
+        loaded: HashSet::new(),
+        config: Config::default(),
+        secrets: Secrets {
+            run_commands,
+            resolved: HashMap::new(),
+        },
+    };
+    match loader.load_path(Path::new(path)) {
+        Ok(()) => Ok(loader.config),
+        Err(message) => Err(ConfigError {
+            file: path.to_string(),
+            document: None,
+            line: None,
+            field: None,
+            message,
+        }),
+    }
+}
+
+/// Resolves the `.` and `..` components of `path` without touching the file system, so
+/// that files are reported as `conf.d/a.conf` rather than `extra/../conf.d/a.conf`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(normal.components().next_back(), Some(Component::Normal(_))) => {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    if normal.as_os_str().is_empty() {
+        normal.push(".");
+    }
+    normal
+}
+
+/// Loads config files into one `Config`, each at most once
+struct Loader {
+    format: Option<ConfigFormat>,
+    /// Canonical paths of the files loaded so far
+    loaded: HashSet<PathBuf>,
+    config: Config,
+    /// Shared by every file, so that a secret is resolved once per load
+    secrets: Secrets,
+}
+
+impl Loader {
+    /// Loads a file, or each config file of a directory. A file of the directory that
+    /// cannot be read is reported as its own problem.
+    fn load_path(&mut self, path: &Path) -> Result<(), String> {
+        if !path.is_dir() {
+            return self.load_file(path);
+        }
+        let mut files = Vec::new();
+        let entries = fs::read_dir(path).map_err(|err| format!("could not read config directory: {}", err))?;
+        for entry in entries {
+            let entry = entry.map_err(|err| format!("could not read config directory: {}", err))?;
+            let file = entry.path();
+            if is_config_file_name(&entry.file_name().to_string_lossy()) && file.is_file() {
+                files.push(file);
+            }
+        }
+        files.sort();
+        self.config.directories.push(path.display().to_string());
+        for file in files {
+            if let Err(message) = self.load_file(&file) {
+                self.config.errors.push(ConfigError {
+                    file: file.display().to_string(),
+                    document: None,
+                    line: None,
+                    field: None,
+                    message,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let file = path.display().to_string();
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if !self.loaded.insert(canonical) {
+            // Included twice, or by a file it includes itself
+            self.config.warnings.push(ConfigError {
+                file,
+                document: None,
+                line: None,
+                field: None,
+                message: "is already loaded; skipped".to_string(),
+            });
+            return Ok(());
+        }
+        let contents = fs::read_to_string(path).map_err(|err| format!("could not read config file: {}", err))?;
+        let format = self.format.unwrap_or_else(|| detect_format(&file));
+        let mut config = parse_with(&contents, &file, format, std::mem::take(&mut self.secrets));
+        self.secrets = std::mem::take(&mut config.secrets);
+        let includes = config.includes.clone();
+        self.config.files.push(file);
+        self.config.directories.append(&mut config.directories);
+        self.config.entries.append(&mut config.entries);
+        self.config.errors.append(&mut config.errors);
+        self.config.warnings.append(&mut config.warnings);
+        self.config.includes.append(&mut config.includes);
+        self.config.defaults.append(&mut config.defaults);
+        self.config.invalid += config.invalid;
+        self.config.documents += config.documents;
+
+        for include in includes {
+            let base = Path::new(&include.file).parent().unwrap_or(Path::new(""));
+            let target = normalize(&base.join(&include.path));
+            if let Err(message) = self.load_path(&target) {
+                self.config.errors.push(ConfigError {
+                    file: include.file,
+                    document: Some(include.document),
+                    line: include.line,
+                    field: Some(include.field),
+                    message: format!("{}: {}", target.display(), message),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The format of `file` by its extension: `.toml`, `.json`, or YAML for anything else
+/// (`.conf`, `.yaml`, `.yml` or none).
+pub fn detect_format(file: &str) -> ConfigFormat {
+    let extension = std::path::Path::new(file)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("toml") => ConfigFormat::Toml,
+        Some("json") => ConfigFormat::Json,
+        _ => ConfigFormat::Yaml,
+    }
+}
+
+/// Validates every entry of `contents`, independently of each other. `cmd:` secrets are
+/// only checked to name a command.
+pub fn parse(contents: &str, file: &str, format: ConfigFormat) -> Config {
+    parse_with(contents, file, format, Secrets::default())
+}
+
+fn parse_with(contents: &str, file: &str, format: ConfigFormat, secrets: Secrets) -> Config {
+    match format {
+        ConfigFormat::Yaml => parse_yaml(contents, file, secrets),
+        ConfigFormat::Toml => parse_toml(contents, file, secrets),
+        ConfigFormat::Json => parse_json(contents, file, secrets),
+    }
+}
+
+/// Each YAML document is an entry. Empty documents are skipped.
+fn parse_yaml(contents: &str, file: &str, secrets: Secrets) -> Config {
+    let yaml = YamlDocuments::parse(contents);
+    let complete = yaml.values.len();
+    let mut config = Config {
+        documents: complete,
+        secrets,
+        ..Config::default()
+    };
+    for (index, document) in yaml.values.into_iter().enumerate() {
+        let mut validator = Validator::new(file, index + 1, yaml.spans.get(index));
+        let raw = match serde_path_to_error::deserialize::<_, Option<EntryConfig>>(document) {
+            Ok(raw) => raw,
+            Err(err) => {
+                validator.path_error(&err.path().to_string(), err.inner().to_string());
+                None
+            }
+        };
+        config.add(validator, raw);
+    }
+    if let Some(err) = yaml.syntax_error {
+        config.invalid += 1;
+        let message = err.to_string();
+        config.errors.push(ConfigError {
+            file: file.to_string(),
+            document: Some(complete + 1),
+            line: Some(err.marker().line()),
+            field: None,
+            message: format!(
+                "invalid YAML: {}",
+                message.rsplit_once(" at line ").map_or(message.as_str(), |(info, _)| info)
+            ),
+        });
+    }
+    config
+}
+
+/// The file is one entry object, or an array of them. `null` items are skipped.
+fn parse_json(contents: &str, file: &str, secrets: Secrets) -> Config {
+    let value: serde_json::Value = match serde_json::from_str(contents) {
+        Ok(value) => value,
+        Err(err) => return Config::invalid(file, Some(err.line()), format!("invalid JSON: {}", err), secrets),
+    };
+    // JSON is also YAML, so the YAML walk finds the lines of the fields
+    let mut spans = YamlDocuments::parse(contents).spans;
+    let documents = match value {
+        serde_json::Value::Array(items) => {
+            spans = spans.pop().map(DocumentSpans::items).unwrap_or_default();
+            items
+        }
+        value => vec![value],
+    };
+    let mut config = Config {
+        documents: documents.len(),
+        secrets,
+        ..Config::default()
+    };
+    for (index, document) in documents.into_iter().enumerate() {
+        let mut validator = Validator::new(file, index + 1, spans.get(index));
+        let raw = match serde_path_to_error::deserialize::<_, Option<EntryConfig>>(document) {
+            Ok(raw) => raw,
+            Err(err) => {
+                validator.path_error(&err.path().to_string(), err.inner().to_string());
+                None
+            }
+        };
+        config.add(validator, raw);
+    }
+    config
+}
+
+/// The file is one entry, or has an `[[entries]]` table per entry.
+fn parse_toml(contents: &str, file: &str, secrets: Secrets) -> Config {
+    let mut table: toml::Table = match toml::from_str(contents) {
+        Ok(table) => table,
+        Err(err) => {
+            let line = err.span().map(|span| contents[..span.start].matches('\n').count() + 1);
+            let message = format!("invalid TOML: {}", err.message().trim().replace('\n', ", "));
+            return Config::invalid(file, line, message, secrets);
+        }
+    };
+    let mut config = Config {
+        secrets,
+        ..Config::default()
+    };
+    let documents = match table.remove("entries") {
+        Some(toml::Value::Array(items)) => {
+            for key in table.keys() {
+                config.errors.push(ConfigError {
+                    file: file.to_string(),
+                    document: None,
+                    line: None,
+                    field: Some(key.clone()),
+                    message: "must be set inside an [[entries]] table".to_string(),
+                });
+            }
+            items
+        }
+        Some(_) => {
+            let message = "entries: must be an array of tables ([[entries]])".to_string();
+            return Config::invalid(file, None, message, config.secrets);
+        }
+        None if table.is_empty() => Vec::new(),
+        None => vec![toml::Value::Table(table)],
+    };
+    config.documents = documents.len();
+    for (index, document) in documents.into_iter().enumerate() {
+        let mut validator = Validator::new(file, index + 1, None);
+        let raw = match serde_path_to_error::deserialize::<_, EntryConfig>(document) {
+            Ok(raw) => Some(raw),
+            Err(err) => {
+                validator.path_error(&err.path().to_string(), err.inner().message().to_string());
+                None
+            }
+        };
+        config.add(validator, raw);
+    }
+    config
+}
+
+impl Config {
+    /// Decides whether the valid entries may run. Any problem is an error listing them all,
+    /// unless `skip_invalid` is set and valid entries remain; then the list is returned as a
+    /// warning.
+    pub fn accept(&self, skip_invalid: bool) -> Result<Option<String>, String> {
+        if self.errors.is_empty() {
+            return Ok(None);
+        }
+        let problems: Vec<String> = self.errors.iter().map(|err| format!("  {}", err)).collect();
+        let count = match problems.len() {
+            1 => "1 problem".to_string(),
+            n => format!("{} problems", n),
+        };
+        if !skip_invalid || self.entries.is_empty() {
+            return Err(format!(
+                "Invalid config ({}):\n{}\nFix them, or pass --skip-invalid to run only the valid entries",
+                count,
+                problems.join("\n")
+            ));
+        }
+        Ok(Some(format!(
+            "Skipping invalid config entries ({}):\n{}",
+            count,
+            problems.join("\n")
+        )))
+    }
+
+    /// A file that could not be parsed at all
+    fn invalid(file: &str, line: Option<usize>, message: String, secrets: Secrets) -> Config {
+        let message = match message.rsplit_once(" at line ") {
+            Some((message, _)) => message.to_string(),
+            None => message,
+        };
+        Config {
+            errors: vec![ConfigError {
+                file: file.to_string(),
+                document: None,
+                line,
+                field: None,
+                message,
+            }],
+            secrets,
+            ..Config::default()
+        }
+    }
+
+    /// Validates one document, adding its entries or its problems.
+    fn add(&mut self, mut validator: Validator, raw: Option<EntryConfig>) {
+        match raw {
+            Some(raw) if raw.include.is_some() => {
+                let mut includes = validate_include(raw, &mut validator);
+                if !validator.errors.is_empty() {
+                    self.invalid += 1;
+                }
+                self.includes.append(&mut includes);
+            }
+            Some(raw) if raw.defaults.is_some() => {
+                self.current_defaults = Some(validate_defaults(raw, &mut validator));
+                self.defaults.push((validator.file.to_string(), validator.document));
+            }
+            Some(raw) => self.add_entries(raw, &mut validator),
+            None if !validator.errors.is_empty() => self.invalid += 1,
+            None => {}
+        }
+        // The entries of a `domains` list share most of their problems; report them once
+        for problems in [&mut validator.errors, &mut validator.warnings] {
+            problems.sort_by(|a, b| (a.line, &a.field, &a.message).cmp(&(b.line, &b.field, &b.message)));
+            problems.dedup_by(|a, b| (a.line, &a.field, &a.message) == (b.line, &b.field, &b.message));
+        }
+        self.errors.append(&mut validator.errors);
+        self.warnings.append(&mut validator.warnings);
+    }
+
+    /// Validates an entry document with the defaults in effect, adding one entry, or one per
+    /// item of `domains`. An invalid item leaves out only its own entry.
+    fn add_entries(&mut self, raw: EntryConfig, v: &mut Validator) {
+        let own_fields = raw.fields_set();
+        let (raw, spans) = match &self.current_defaults {
+            Some(defaults) => {
+                let mut spans = v.spans.cloned();
+                if let (Some(spans), Some(default_spans)) = (spans.as_mut(), &defaults.spans) {
+                    spans.overlay(default_spans);
+                }
+                (raw.with_defaults(&defaults.raw), spans)
+            }
+            None => (raw, v.spans.cloned()),
+        };
+        // Defaults shared by several providers' entries are not worth a warning where they
+        // do not apply
+        let inherited: Vec<_> = raw.fields_set().into_iter().filter(|field| !own_fields.contains(field)).collect();
+
+        let Some(domains) = raw.domains.clone() else {
+            let mut entry_validator = Validator::new(v.file, v.document, spans.as_ref());
+            entry_validator.secrets = Some(&mut self.secrets);
+            entry_validator.inherited = inherited;
+            match validate(raw, &mut entry_validator) {
+                Some(entry) => self.entries.push(entry),
+                None => self.invalid += 1,
+            }
+            v.absorb(entry_validator, None);
+            return;
+        };
+        if raw.domain.is_some() {
+            v.error("domain", "cannot be combined with domains; list every domain under domains");
+        }
+        if domains.is_empty() {
+            v.error("domains", "must not be empty");
+        }
+        if !v.errors.is_empty() {
+            self.invalid += 1;
+            return;
+        }
+
+        for (i, item) in domains.into_iter().enumerate() {
+            let path = format!("domains[{}]", i);
+            let mut host = EntryConfig {
+                domains: None,
+                ..raw.clone()
+            };
+            let mut host_spans = spans.clone();
+            let mut overridden = vec!["domain"];
+            let mut unknown = BTreeMap::new();
+            match item {
+                DomainConfig::Name(domain) => host.domain = Some(domain),
+                DomainConfig::Detailed(item) => {
+                    host.domain = item.domain;
+                    if let Some(records) = item.records {
+                        host.records = Some(records);
+                        overridden.push("records");
+                    }
+                    if let Some(ttl) = item.ttl {
+                        host.ttl = Some(ttl);
+                        overridden.push("ttl");
+                    }
+                    if let Some(ip_source) = item.ip_source {
+                        host.ip_source = Some(ip_source);
+                        overridden.push("ip_source");
+                    }
+                    unknown = item.unknown;
+                    if let Some(spans) = host_spans.as_mut() {
+                        let item_spans = spans.section(&path);
+                        spans.fields.extend(item_spans.fields);
+                        spans.start = item_spans.start;
+                    }
+                }
+            }
+            let mut host_validator = Validator::new(v.file, v.document, host_spans.as_ref());
+            host_validator.secrets = Some(&mut self.secrets);
+            host_validator.inherited = inherited.clone();
+            host_validator.unknown(&format!("{}.", path), &unknown);
+            match validate(host, &mut host_validator) {
+                Some(entry) if host_validator.errors.is_empty() => self.entries.push(entry),
+                _ => self.invalid += 1,
+            }
+            v.absorb(host_validator, Some((&path, &overridden)));
+        }
+    }
+}
+
+/// Checks a `defaults` document, which must have no entry fields of its own, returning the
+/// defaults to apply. Fields that cannot be defaults are dropped.
+fn validate_defaults(raw: EntryConfig, v: &mut Validator) -> Defaults {
+    v.unknown("", &raw.unknown);
+    for field in raw.fields_set() {
+        v.error(field, "cannot be combined with defaults; put the entry in a document of its own");
+    }
+    let mut defaults = *raw.defaults.unwrap_or_default();
+    v.unknown("defaults.", &defaults.unknown);
+    let mut forbidden = Vec::new();
+    if defaults.domain.take().is_some() {
+        forbidden.push("domain");
+    }
+    if defaults.domains.take().is_some() {
+        forbidden.push("domains");
+    }
+    if defaults.include.take().is_some() {
+        forbidden.push("include");
+    }
+    if defaults.defaults.take().is_some() {
+        forbidden.push("defaults");
+    }
+    for field in forbidden {
+        v.error(&format!("defaults.{}", field), "cannot be a default");
+    }
+    Defaults {
+        raw: defaults,
+        spans: v.spans.map(|spans| spans.section("defaults")),
+    }
+}
+
+/// Collects the problems of one document
+struct Validator<'a> {
+    file: &'a str,
+    document: usize,
+    spans: Option<&'a DocumentSpans>,
+    /// Where secrets are resolved, for the validators of entries
+    secrets: Option<&'a mut Secrets>,
+    /// Fields filled in from a `defaults` document, which `ignored` does not warn about
+    inherited: Vec<&'static str>,
+    errors: Vec<ConfigError>,
+    warnings: Vec<ConfigError>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(file: &'a str, document: usize, spans: Option<&'a DocumentSpans>) -> Validator<'a> {
+        Validator {
+            file,
+            document,
+            spans,
+            secrets: None,
+            inherited: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, field: &str, message: impl Into<String>) {
+        let error = self.locate(field, message.into());
+        self.errors.push(error);
+    }
+
+    fn warning(&mut self, field: &str, message: impl Into<String>) {
+        let warning = self.locate(field, message.into());
+        self.warnings.push(warning);
+    }
+
+    fn locate(&self, field: &str, message: String) -> ConfigError {
+        ConfigError {
+            file: self.file.to_string(),
+            document: Some(self.document),
+            line: self.spans.map(|spans| spans.line(field)),
+            field: Some(field.to_string()),
+            message,
+        }
+    }
+
+    /// Warns about each of `fields` that is set but not used by `provider`.
+    fn ignored(&mut self, raw: &EntryConfig, fields: &[&str], provider: &str) {
+        for field in raw.fields_set() {
+            if fields.contains(&field) && !self.inherited.contains(&field) {
+                self.warning(field, format!("is ignored for {}", provider));
+            }
+        }
+    }
+
+    /// Records a type error of the document as a whole, at `line`.
+    fn serde_error(&mut self, message: String, line: Option<usize>) {
+        self.errors.push(ConfigError {
+            file: self.file.to_string(),
+            document: Some(self.document),
+            line,
+            field: None,
+            message,
+        });
+    }
+
+    /// Records a type error at `path`, as tracked by serde_path_to_error (`.` for the
+    /// document itself).
+    fn path_error(&mut self, path: &str, message: String) {
+        if path == "." {
+            self.serde_error(message, self.spans.map(|spans| spans.start));
+        } else {
+            self.error(path, message);
+        }
+    }
+
+    /// Takes over the problems of a validator for one entry of this document. With `item`,
+    /// the entry is an item of `domains`, and problems in the fields it overrides are
+    /// reported under the item's path.
+    fn absorb(&mut self, mut other: Validator, item: Option<(&str, &[&str])>) {
+        if let Some((path, overridden)) = item {
+            for problem in other.errors.iter_mut().chain(other.warnings.iter_mut()) {
+                if let Some(field) = problem.field.as_mut() {
+                    let root = field.split(['.', '[']).next().unwrap_or_default();
+                    if overridden.contains(&root) {
+                        *field = format!("{}.{}", path, field);
+                    }
+                }
+            }
+        }
+        self.errors.append(&mut other.errors);
+        self.warnings.append(&mut other.warnings);
+    }
+
+    fn required(&mut self, field: &str, value: Option<String>, message: &str) -> Option<String> {
+        if value.is_none() {
+            self.error(field, message);
+        }
+        value
+    }
+
+    fn resolve_secret(&mut self, value: &str) -> Result<(String, Option<String>), String> {
+        match self.secrets.as_deref_mut() {
+            Some(secrets) => secrets.resolve(value),
+            None => resolve_secret(value),
+        }
+    }
+
+    /// Resolves the secret in `field`, which must be set.
+    fn secret(&mut self, field: &str, value: Option<&str>, message: &str) -> Option<String> {
+        match value {
+            Some(value) => match self.resolve_secret(value) {
+                Ok((secret, warning)) => {
+                    if let Some(warning) = warning {
+                        self.warning(field, warning);
+                    }
+                    Some(secret)
+                }
+                Err(err) => {
+                    self.error(field, err);
+                    None
+                }
+            },
+            None => {
+                self.error(field, message);
+                None
+            }
+        }
+    }
+
+    fn unknown(&mut self, prefix: &str, unknown: &BTreeMap<String, IgnoredAny>) {
+        for key in unknown.keys() {
+            self.error(&format!("{}{}", prefix, key), "unknown field");
+        }
+    }
+}
+
+/// Checks an `include` document, which must have no entry fields of its own, returning
+/// the paths to load if it has no problems.
+fn validate_include(raw: EntryConfig, v: &mut Validator) -> Vec<Include> {
+    v.unknown("", &raw.unknown);
+    for field in raw.fields_set() {
+        v.error(field, "cannot be combined with include; put the entry in a document of its own");
+    }
+    let paths = match raw.include {
+        Some(PathsConfig::One(path)) => vec![("include".to_string(), path)],
+        Some(PathsConfig::Many(paths)) if paths.is_empty() => {
+            v.error("include", "must not be empty");
+            Vec::new()
+        }
+        Some(PathsConfig::Many(paths)) => paths
+            .into_iter()
+            .enumerate()
+            .map(|(i, path)| (format!("include[{}]", i), path))
+            .collect(),
+        None => Vec::new(),
+    };
+    for (field, path) in &paths {
+        if path.trim().is_empty() {
+            v.error(field, "must not be empty");
+        }
+    }
+    if !v.errors.is_empty() {
+        return Vec::new();
+    }
+    paths
+        .into_iter()
+        .map(|(field, path)| Include {
+            file: v.file.to_string(),
+            document: v.document,
+            line: v.spans.map(|spans| spans.line(&field)),
+            field,
+            path,
+        })
+        .collect()
+}
+
+/// Checks one document, returning its entry if it has no problems.
+fn validate(raw: EntryConfig, v: &mut Validator) -> Option<Entry> {
+    v.unknown("", &raw.unknown);
+    let domain = v.required("domain", raw.domain.clone(), "is required");
+    let server = v.required("server", raw.server.clone(), "is required");
+
+    let records = match &raw.records {
+        None => vec![Record::A],
+        Some(records) if records.is_empty() => {
+            v.error("records", "must not be empty");
+            Vec::new()
+        }
+        Some(records) => records
+            .iter()
+            .enumerate()
+            .filter_map(|(i, record)| match Record::from_str(record) {
+                Ok(record) => Some(record),
+                Err(_) => {
+                    v.error(&format!("records[{}]", i), format!("must be A or AAAA (got '{}')", record));
+                    None
+                }
+            })
+            .collect(),
+    };
+
+    let provider = server.and_then(|server| validate_provider(&server, &raw, &records, v));
+
+    let nameservers = raw
+        .nameservers
+        .iter()
+        .flatten()
+        .enumerate()
+        .filter_map(|(i, nameserver)| match parse_socket_address(nameserver, 53) {
+            Ok(address) => Some(address),
+            Err(err) => {
+                v.error(&format!("nameservers[{}]", i), err);
+                None
+            }
+        })
+        .collect();
+
+    let ip_source = match &raw.ip_source {
+        Some(source) => validate_ip_source(source, v),
+        None => Some(IpSource::default()),
+    };
+
+    let comparison = match raw.compare.as_deref().map(|compare| (compare, Comparison::from_str(compare))) {
+        None => None,
+        Some((_, Ok(Comparison::Provider))) if raw.server.as_deref() == Some("namecheap") => {
+            v.error(
+                "compare",
+                "'provider' is not supported for Namecheap, which has no API to read records",
+            );
+            None
+        }
+        Some((_, Ok(comparison))) => Some(comparison),
+        Some((compare, Err(_))) => {
+            v.error(
+                "compare",
+                format!("must be dns, authoritative or provider (got '{}')", compare),
+            );
+            None
+        }
+    };
+
+    let max_age = match &raw.max_age {
+        None => None,
+        Some(DurationConfig::Seconds(secs)) if *secs > 0 => Some(*secs as u64),
+        Some(DurationConfig::Text(duration)) => {
+            match time_tools::parse_duration_secs(duration).filter(|secs| *secs > 0) {
+                Some(secs) => Some(secs),
+                None => {
+                    v.error(
+                        "max_age",
+                        format!("must be a duration such as 30d, 12h or a number of seconds (got '{}')", duration),
+                    );
+                    None
+                }
+            }
+        }
+        Some(DurationConfig::Seconds(secs)) => {
+            v.error("max_age", format!("must be greater than zero (got {})", secs));
+            None
+        }
+    };
+
+    if !v.errors.is_empty() {
+        return None;
+    }
+    Some(Entry {
+        file: v.file.to_string(),
+        document: v.document,
+        domain: domain?,
+        provider: provider?,
+        records,
+        nameservers,
+        ip_source: ip_source?,
+        comparison,
+        max_age,
+    })
+}
+
+fn validate_provider(server: &str, raw: &EntryConfig, records: &[Record], v: &mut Validator) -> Option<Provider> {
+    match server {
+        "domains.google.com" => {
+            v.error("server", "Google Domains DDNS (domains.google.com) is no longer supported. Google sold Domains to Squarespace, which dropped DDNS support. Please migrate to Cloudflare: update your config to use 'server: cloudflare' with an 'api_token'. See README for migration steps.");
+            None
+        }
+        "cloudflare" => {
+            v.ignored(raw, &["username", "password", "methods"], "Cloudflare");
+            let ttl = validate_ttl(raw.ttl.as_ref(), v);
+            let api_token = v.secret("api_token", raw.api_token.as_deref(), "is required for Cloudflare")?;
+            Some(Provider::Cloudflare { api_token, ttl: ttl? })
+        }
+        "namecheap" => {
+            v.ignored(raw, &["username", "api_token", "methods", "ttl"], "Namecheap");
+            for (i, record) in records.iter().enumerate() {
+                if let Record::Aaaa = record {
+                    v.error(&format!("records[{}]", i), "Namecheap DDNS does not support AAAA records");
+                }
+            }
+            let password = v.secret("password", raw.password.as_deref(), "is required for Namecheap")?;
+            Some(Provider::Namecheap { password })
+        }
+        _ => {
+            v.ignored(raw, &["api_token", "ttl"], "Mail-in-a-Box");
+            let username = v.secret("username", raw.username.as_deref(), "is required");
+            let password = v.secret("password", raw.password.as_deref(), "is required");
+            let methods = match &raw.methods {
+                None => {
+                    v.error("methods", "is required (a list of POST, PUT or DELETE)");
+                    None
+                }
+                Some(methods) if methods.is_empty() => {
+                    v.error("methods", "must not be empty");
+                    None
+                }
+                Some(methods) => {
+                    let mut parsed = Vec::new();
+                    for (i, method) in methods.iter().enumerate() {
+                        match Method::from_str(method) {
+                            Ok(method) => parsed.push(method),
+                            Err(_) => v.error(
+                                &format!("methods[{}]", i),
+                                format!("must be PUT, POST or DELETE (got '{}')", method),
+                            ),
+                        }
+                    }
+                    Some(parsed)
+                }
+            };
+            Some(Provider::MailInABox {
+                server: server.to_string(),
+                username: username?,
+                password: password?,
+                methods: methods?,
+            })
+        }
+    }
+}
+
+/// Checks the Cloudflare `ttl`: `auto` (1) or 30 seconds to a day, as Cloudflare allows.
+/// Returns `Some(None)` if it is not set, and `None` if it is invalid.
+fn validate_ttl(ttl: Option<&DurationConfig>, v: &mut Validator) -> Option<Option<u32>> {
+    let secs = match ttl {
+        None => return Some(None),
+        Some(DurationConfig::Text(text)) if text == "auto" => Some(1),
+        Some(DurationConfig::Text(text)) => time_tools::parse_duration_secs(text),
+        Some(DurationConfig::Seconds(secs)) => u64::try_from(*secs).ok(),
+    };
+    match secs {
+        Some(secs) if secs == 1 || (30..=86400).contains(&secs) => Some(Some(secs as u32)),
+        _ => {
+            let got = match ttl {
+                Some(DurationConfig::Text(text)) => text.clone(),
+                Some(DurationConfig::Seconds(secs)) => secs.to_string(),
+                None => String::new(),
+            };
+            v.error("ttl", format!("must be auto or between 30s and 1d (got '{}')", got));
+            None
+        }
+    }
+}
+
+/// Checks the `ip_source` section; without one the default ipify endpoints are used.
+fn validate_ip_source(source: &IpSourceConfig, v: &mut Validator) -> Option<IpSource> {
+    v.unknown("ip_source.", &source.unknown);
+    match source.kind.as_deref().unwrap_or("http") {
+        "http" => validate_http_source(source, v).map(IpSource::Http),
+        "interface" => match &source.name {
+            Some(name) => Some(IpSource::Interface(InterfaceSource { name: name.clone() })),
+            None => {
+                v.error("ip_source.name", "is required for the interface source");
+                None
+            }
+        },
+        "router" => validate_router_source(source, v).map(IpSource::Router),
+        "dns" => validate_dns_source(source, v).map(IpSource::Dns),
+        "stun" => {
+            let mut stun = StunSource::default();
+            if let Some(servers) = &source.servers {
+                if servers.is_empty() {
+                    v.error("ip_source.servers", "must not be empty");
+                    return None;
+                }
+                stun.servers = servers
+                    .iter()
+                    .map(|server| with_default_port(server, 3478))
+                    .collect();
+            }
+            Some(IpSource::Stun(stun))
+        }
+        other => {
+            v.error(
+                "ip_source.type",
+                format!("must be http, interface, router, dns or stun (got '{}')", other),
+            );
+            None
+        }
+    }
+}
+
+fn validate_http_source(source: &IpSourceConfig, v: &mut Validator) -> Option<HttpSource> {
+    let endpoints = match &source.endpoints {
+        Some(endpoints) if endpoints.is_empty() => {
+            v.error("ip_source.endpoints", "must not be empty");
+            return None;
+        }
+        Some(endpoints) => {
+            let parsed: Vec<Option<Endpoint>> = endpoints
+                .iter()
+                .enumerate()
+                .map(|(i, endpoint)| validate_endpoint(endpoint, &format!("ip_source.endpoints[{}]", i), v))
+                .collect();
+            parsed.into_iter().collect::<Option<Vec<_>>>()?
+        }
+        None => HttpSource::default().endpoints,
+    };
+    let strategy = match source.strategy.as_deref().unwrap_or("fallback") {
+        "fallback" if source.quorum.is_some() => {
+            v.error("ip_source.quorum", "only applies to strategy: consensus");
+            return None;
+        }
+        "fallback" => Strategy::Fallback,
+        "consensus" => Strategy::Consensus(source.quorum.map(|q| q as usize)),
+        other => {
+            v.error(
+                "ip_source.strategy",
+                format!("must be fallback or consensus (got '{}')", other),
+            );
+            return None;
+        }
+    };
+    let http = HttpSource { endpoints, strategy };
+    // Only the endpoints of the family being looked up vote, so the quorum must be
+    // reachable with each family's own endpoints
+    if let (Strategy::Consensus(_), Some(quorum)) = (&http.strategy, source.quorum) {
+        for family in [Family::V4, Family::V6] {
+            let count = http.endpoints_for(family).len();
+            if count > 0 && !(1..=count as i64).contains(&quorum) {
+                v.error(
+                    "ip_source.quorum",
+                    format!(
+                        "must be between 1 and the number of {} endpoints ({}), got {}",
+                        family, count, quorum
+                    ),
+                );
+                return None;
+            }
+        }
+    }
+    Some(http)
+}
+
+fn validate_endpoint(endpoint: &EndpointConfig, field: &str, v: &mut Validator) -> Option<Endpoint> {
+    let (url, format, json_field, pattern, family) = match endpoint {
+        EndpointConfig::Url(url) => {
+            return Some(Endpoint {
+                url: url.clone(),
+                format: ResponseFormat::Text,
+                family: None,
+            })
+        }
+        EndpointConfig::Detailed { url, format, field, pattern, family } => (url, format, field, pattern, family),
+    };
+    let url = match url {
+        Some(url) => url.clone(),
+        None => {
+            v.error(&format!("{}.url", field), "is required");
+            return None;
+        }
+    };
+    let format = match format.as_deref().unwrap_or("text") {
+        "text" => ResponseFormat::Text,
+        "json" => match json_field {
+            Some(json_field) => ResponseFormat::Json(json_field.clone()),
+            None => {
+                v.error(&format!("{}.field", field), "is required with format json");
+                return None;
+            }
+        },
+        "regex" => match pattern {
+            Some(pattern) => {
+                if let Err(err) = regex::Regex::new(pattern) {
+                    v.error(&format!("{}.pattern", field), format!("is not a valid regex: {}", err));
+                    return None;
+                }
+                ResponseFormat::Regex(pattern.clone())
+            }
+            None => {
+                v.error(&format!("{}.pattern", field), "is required with format regex");
+                return None;
+            }
+        },
+        other => {
+            v.error(
+                &format!("{}.format", field),
+                format!("must be text, json or regex (got '{}')", other),
+            );
+            return None;
+        }
+    };
+    let family = match family.as_deref() {
+        None => None,
+        Some("ipv4") => Some(Family::V4),
+        Some("ipv6") => Some(Family::V6),
+        Some(other) => {
+            v.error(
+                &format!("{}.family", field),
+                format!("must be ipv4 or ipv6 (got '{}')", other),
+            );
+            return None;
+        }
+    };
+    Some(Endpoint { url, format, family })
+}
+
+fn validate_router_source(source: &IpSourceConfig, v: &mut Validator) -> Option<RouterSource> {
+    let mut router = RouterSource::default();
+    let mut valid = true;
+    if let Some(protocols) = &source.protocols {
+        router.protocols = Vec::new();
+        for (i, protocol) in protocols.iter().enumerate() {
+            match RouterProtocol::from_str(protocol) {
+                Ok(protocol) => router.protocols.push(protocol),
+                Err(_) => {
+                    v.error(
+                        &format!("ip_source.protocols[{}]", i),
+                        format!("must be upnp, natpmp or pcp (got '{}')", protocol),
+                    );
+                    valid = false;
+                }
+            }
+        }
+    }
+    if let Some(gateway) = &source.gateway {
+        match parse_socket_address(gateway, 5351) {
+            Ok(gateway) => router.gateway = Some(gateway),
+            Err(err) => {
+                v.error("ip_source.gateway", err);
+                valid = false;
+            }
+        }
+    }
+    if let Some(ssdp_address) = &source.ssdp_address {
+        match parse_socket_address(ssdp_address, 1900) {
+            Ok(ssdp_address) => router.ssdp_address = Some(ssdp_address),
+            Err(err) => {
+                v.error("ip_source.ssdp_address", err);
+                valid = false;
+            }
+        }
+    }
+    valid.then_some(router)
+}
+
+fn validate_dns_source(source: &IpSourceConfig, v: &mut Validator) -> Option<DnsSource> {
+    let provider = match source.provider.as_deref() {
+        None => Some(DnsProvider::OpenDns),
+        Some(provider) => match DnsProvider::from_str(provider) {
+            Ok(provider) => Some(provider),
+            Err(_) => {
+                v.error(
+                    "ip_source.provider",
+                    format!("must be opendns, cloudflare or google (got '{}')", provider),
+                );
+                None
+            }
+        },
+    };
+    let resolvers = match &source.resolvers {
+        Some(resolvers) => {
+            let parsed: Vec<Option<SocketAddr>> = resolvers
+                .iter()
+                .enumerate()
+                .map(|(i, resolver)| match parse_socket_address(resolver, 53) {
+                    Ok(address) => Some(address),
+                    Err(err) => {
+                        v.error(&format!("ip_source.resolvers[{}]", i), err);
+                        None
+                    }
+                })
+                .collect();
+            Some(parsed.into_iter().collect::<Option<Vec<_>>>()?)
+        }
+        None => None,
+    };
+    Some(DnsSource { provider: provider?, resolvers })
+}
+
+/// Parses `host:port` or a bare IP address, which gets `default_port`.
+fn parse_socket_address(value: &str, default_port: u16) -> Result<SocketAddr, String> {
+    if let Ok(address) = SocketAddr::from_str(value) {
+        return Ok(address);
+    }
+    match IpAddr::from_str(value) {
+        Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+        Err(_) => Err(format!("'{}' is not a valid IP address or IP:port", value)),
+    }
+}
+
+/// Adds `default_port` to a host name or IP address without one. IPv6 addresses, bare or
+/// in brackets, come out as `[address]:port`.
+fn with_default_port(server: &str, default_port: u16) -> String {
+    if SocketAddr::from_str(server).is_ok() {
+        return server.to_string();
+    }
+    let host = server.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(server);
+    match IpAddr::from_str(host) {
+        Ok(ip) => SocketAddr::new(ip, default_port).to_string(),
+        Err(_) if server.contains(':') => server.to_string(),
+        Err(_) => format!("{}:{}", server, default_port),
+    }
+}
+
+/// How long a `cmd:` secret command may run
+const SECRET_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Resolves a secret value:
+/// - `env:NAME` reads the environment variable `NAME`
+/// - `file:PATH` reads the file at `PATH`; see `read_secret_file`
+/// - `cmd:COMMAND` runs `COMMAND` with the shell and takes its output
+///
+/// File contents and command output are trimmed. Anything else is used literally. Returns
+/// the secret and a warning about how it is kept, if any.
+pub fn resolve_secret(value: &str) -> Result<(String, Option<String>), String> {
+    let mut warning = None;
+    let secret = if let Some(var_name) = value.strip_prefix("env:") {
+        match std::env::var(var_name) {
+            Ok(v) if !v.is_empty() => return Ok((v, None)),
+            _ => return Err(format!("env var '{}' is not set", var_name)),
+        }
+    } else if let Some(path) = value.strip_prefix("file:") {
+        let (secret, exposed) = read_secret_file(path)?;
+        warning = exposed;
+        secret
+    } else if let Some(command) = value.strip_prefix("cmd:") {
+        run_secret_command(command, SECRET_COMMAND_TIMEOUT)?
+    } else {
+        return Ok((value.to_string(), None));
+    };
+    let secret = secret.trim();
+    if secret.is_empty() {
+        let source = value.split_once(':').map_or(value, |(prefix, _)| prefix);
+        return Err(format!("{}: secret is empty", source));
+    }
+    Ok((secret.to_string(), warning))
+}
+
+/// Reads a secret file, with a warning if other local users can read it too.
+///
+/// On Unix a file readable by group or others gets that warning, unless it is owned by
+/// root and cannot be changed: read-only, or on a read-only mount. That is how Docker
+/// (0444) and Kubernetes (0644 on a read-only volume) provide secrets, and there the
+/// container's users are the ones who should read them.
+fn read_secret_file(path: &str) -> Result<(String, Option<String>), String> {
+    let metadata = fs::metadata(path).map_err(|err| format!("could not read secret file '{}': {}", path, err))?;
+    #[cfg(unix)]
+    let warning = {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let mode = metadata.permissions().mode() & 0o777;
+        let managed = metadata.uid() == 0 && (mode & 0o222 == 0 || on_read_only_mount(path));
+        (mode & 0o044 != 0 && !managed).then(|| {
+            format!(
+                "secret file '{}' is readable by group or others (mode {:03o}), so other local users can read \
+                 the secret; restrict it with chmod 600",
+                path, mode
+            )
+        })
+    };
+    #[cfg(not(unix))]
+    let warning = {
+        let _ = metadata;
+        None
+    };
+    let secret = fs::read_to_string(path).map_err(|err| format!("could not read secret file '{}': {}", path, err))?;
+    Ok((secret, warning))
+}
+
+/// Whether the file system holding `path` is mounted read-only
+#[cfg(unix)]
+fn on_read_only_mount(path: &str) -> bool {
+    let Ok(path) = std::ffi::CString::new(path) else {
+        return false;
+    };
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    unsafe { libc::statvfs(path.as_ptr(), &mut stat) == 0 && stat.f_flag & libc::ST_RDONLY != 0 }
+}
+
+/// Runs `command` with `sh -c` (`cmd /C` on Windows), without stdin, and returns its
+/// output. A command that fails or runs longer than `timeout` is an error naming its
+/// program and its first line of stderr. The full command line is never part of an
+/// error, since it may hold secret material itself.
+fn run_secret_command(command: &str, timeout: Duration) -> Result<String, String> {
+    use std::process::{Command, Stdio};
+
+    let Some(program) = command.split_whitespace().next() else {
+        return Err("cmd: must be followed by a command".to_string());
+    };
+
+    #[cfg(not(target_os = "windows"))]
+    let mut shell = Command::new("sh");
+    #[cfg(not(target_os = "windows"))]
+    shell.arg("-c");
+    #[cfg(target_os = "windows")]
+    let mut shell = Command::new("cmd");
+    #[cfg(target_os = "windows")]
+    shell.arg("/C");
+
+    let mut child = shell
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("could not run secret command '{}': {}", program, err))?;
+    // Read the pipes while waiting, so a chatty command cannot block on a full pipe
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stdout = std::thread::spawn(move || {
+        let mut output = String::new();
+        std::io::Read::read_to_string(&mut stdout, &mut output).map(|_| output)
+    });
+    let stderr = std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = std::io::Read::read_to_string(&mut stderr, &mut output);
+        output
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() < timeout => std::thread::sleep(Duration::from_millis(20)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("secret command '{}' did not finish within {:?}", program, timeout));
+            }
+            Err(err) => return Err(format!("could not run secret command '{}': {}", program, err)),
+        }
+    };
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        let reason = stderr.lines().find(|line| !line.trim().is_empty()).unwrap_or("no error output");
+        return Err(format!("secret command '{}' failed ({}): {}", program, status, reason.trim()));
+    }
+    match stdout.join() {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(err)) => Err(format!("could not read the output of secret command '{}': {}", program, err)),
+        Err(_) => Err(format!("could not read the output of secret command '{}'", program)),
+    }
+}
+
+/// The YAML documents of a file: the value of each, and the line numbers of its keys and
+/// list items by field path such as `ip_source.endpoints[1]`
+#[derive(Debug, Default)]
+struct YamlDocuments {
+    /// The documents parsed to the end
+    values: Vec<YamlValue>,
+    /// Also has the document with a syntax error, if any
+    spans: Vec<DocumentSpans>,
+    syntax_error: Option<ScanError>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct DocumentSpans {
+    start: usize,
+    fields: BTreeMap<String, usize>,
+}
+
+impl DocumentSpans {
+    /// The spans of the value at `path`, by paths relative to it.
+    fn section(&self, path: &str) -> DocumentSpans {
+        let fields = self
+            .fields
+            .iter()
+            .filter_map(|(field, line)| {
+                let field = field.strip_prefix(path)?.strip_prefix('.')?;
+                Some((field.to_string(), *line))
+            })
+            .collect();
+        DocumentSpans {
+            start: self.line(path),
+            fields,
+        }
+    }
+
+    /// Adds the lines of `other`'s fields that this document does not have itself.
+    fn overlay(&mut self, other: &DocumentSpans) {
+        for (field, line) in &other.fields {
+            self.fields.entry(field.clone()).or_insert(*line);
+        }
+    }
+
+    /// Splits the spans of a document that is a list into one per item, e.g. for a JSON
+    /// array of entries.
+    fn items(self) -> Vec<DocumentSpans> {
+        let mut items: Vec<DocumentSpans> = Vec::new();
+        for (path, line) in self.fields {
+            let Some((index, rest)) = path.strip_prefix('[').and_then(|path| path.split_once(']')) else {
+                continue;
+            };
+            let Ok(index) = index.parse::<usize>() else {
+                continue;
+            };
+            if items.len() <= index {
+                items.resize_with(index + 1, DocumentSpans::default);
+            }
+            match rest.strip_prefix('.') {
+                Some(field) => {
+                    items[index].fields.insert(field.to_string(), line);
+                }
+                None if rest.is_empty() => items[index].start = line,
+                None => {
+                    items[index].fields.insert(rest.to_string(), line);
+                }
+            }
+        }
+        items
+    }
+
+    /// The line of `field`, or of its closest parent that appears in the document (a
+    /// missing field is reported at the start of its section).
+    fn line(&self, field: &str) -> usize {
+        let mut path = field;
+        loop {
+            if let Some(line) = self.fields.get(path) {
+                return *line;
+            }
+            match path.rfind(['.', '[']) {
+                Some(end) => path = &path[..end],
+                None => return self.start,
+            }
+        }
+    }
+}
+
+/// A YAML value as written. Plain scalars keep their text, so a field expecting a string
+/// reads `12345` or `0x10` as written, as serde_yaml does, while other fields get the value
+/// typed by the YAML 1.2 core schema.
+#[derive(Debug, Clone)]
+enum YamlValue {
+    /// Quoted scalars and those tagged `!!str` are not `plain`
+    Scalar { text: String, plain: bool },
+    Seq(Vec<YamlValue>),
+    Map(Vec<(String, YamlValue)>),
+}
+
+impl YamlValue {
+    fn null() -> YamlValue {
+        YamlValue::Scalar {
+            text: "~".to_string(),
+            plain: true,
+        }
+    }
+
+    /// The typed value of a plain scalar
+    fn typed(&self) -> Option<Yaml> {
+        match self {
+            YamlValue::Scalar { text, plain: true } => Some(Yaml::from_str(text)),
+            _ => None,
+        }
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for YamlValue {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match (self.typed(), self) {
+            (Some(Yaml::Null), _) => visitor.visit_unit(),
+            (Some(Yaml::Boolean(b)), _) => visitor.visit_bool(b),
+            (Some(Yaml::Integer(i)), _) => visitor.visit_i64(i),
+            (Some(Yaml::Real(real)), YamlValue::Scalar { text, .. }) => match real.parse::<f64>() {
+                Ok(real) => visitor.visit_f64(real),
+                Err(_) => visitor.visit_string(text),
+            },
+            (_, YamlValue::Scalar { text, .. }) => visitor.visit_string(text),
+            (_, YamlValue::Seq(items)) => {
+                let mut seq = serde::de::value::SeqDeserializer::new(items.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            (_, YamlValue::Map(entries)) => {
+                let mut map = serde::de::value::MapDeserializer::new(entries.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn deserialize_option<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.typed() {
+            Some(Yaml::Null) => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_str<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match (self.typed(), self) {
+            (Some(Yaml::Null), _) => visitor.visit_unit(),
+            (_, YamlValue::Scalar { text, .. }) => visitor.visit_string(text),
+            (_, value) => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit unit_struct
+        newtype_struct seq tuple tuple_struct map struct enum identifier
+    }
+}
+
+impl<'de> serde::de::IntoDeserializer<'de, serde::de::value::Error> for YamlValue {
+    type Deserializer = YamlValue;
+
+    fn into_deserializer(self) -> YamlValue {
+        self
+    }
+}
+
+/// A mapping or sequence being walked: the path of the value it is at, its anchor and its
+/// contents so far
+enum Frame {
+    Map {
+        path: String,
+        anchor: usize,
+        /// The key whose value comes next, and its path
+        key: Option<(String, String)>,
+        entries: Vec<(String, YamlValue)>,
+    },
+    Seq {
+        path: String,
+        anchor: usize,
+        items: Vec<YamlValue>,
+    },
+}
+
+impl YamlDocuments {
+    /// Walks the YAML events of `contents`. A syntax error ends the walk.
+    fn parse(contents: &str) -> YamlDocuments {
+        let mut collector = YamlCollector {
+            documents: YamlDocuments::default(),
+            stack: Vec::new(),
+            anchors: HashMap::new(),
+            root: None,
+        };
+        if let Err(err) = Parser::new(contents.chars()).load(&mut collector, true) {
+            collector.documents.syntax_error = Some(err);
+        }
+        collector.documents
+    }
+}
+
+struct YamlCollector {
+    documents: YamlDocuments,
+    stack: Vec<Frame>,
+    /// Values by anchor id, for aliases
+    anchors: HashMap<usize, YamlValue>,
+    /// The value of the current document once it is complete
+    root: Option<YamlValue>,
+}
+
+impl YamlCollector {
+    /// Starts a value at `mark`, returning its path.
+    fn enter(&mut self, mark: Marker) -> String {
+        let path = match self.stack.last() {
+            Some(Frame::Map { key: Some((_, key)), .. }) => key.clone(),
+            Some(Frame::Map { path, key: None, .. }) => path.clone(),
+            Some(Frame::Seq { path, items, .. }) => format!("{}[{}]", path, items.len()),
+            None => String::new(),
+        };
+        if matches!(self.stack.last(), Some(Frame::Seq { .. })) {
+            self.record(&path, mark);
+        }
+        path
+    }
+
+    /// Finishes `value`, adding it to its parent, which moves on to the next key or item.
+    fn leave(&mut self, value: YamlValue, anchor: usize) {
+        if anchor != 0 {
+            self.anchors.insert(anchor, value.clone());
+        }
+        match self.stack.last_mut() {
+            Some(Frame::Map { key, entries, .. }) => {
+                if let Some((name, _)) = key.take() {
+                    entries.push((name, value));
+                }
+            }
+            Some(Frame::Seq { items, .. }) => items.push(value),
+            None => self.root = Some(value),
+        }
+    }
+
+    fn record(&mut self, path: &str, mark: Marker) {
+        if let Some(document) = self.documents.spans.last_mut() {
+            document.fields.entry(path.to_string()).or_insert(mark.line());
+        }
+    }
+}
+
+impl MarkedEventReceiver for YamlCollector {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::DocumentStart => {
+                self.documents.spans.push(DocumentSpans {
+                    start: mark.line(),
+                    ..DocumentSpans::default()
+                });
+                self.stack.clear();
+                self.root = None;
+            }
+            Event::MappingStart(anchor) => {
+                // Report a document's missing fields at its first key rather than at `---`
+                if self.stack.is_empty() {
+                    if let Some(document) = self.documents.spans.last_mut() {
+                        document.start = mark.line();
+                    }
+                }
+                let path = self.enter(mark);
+                self.stack.push(Frame::Map {
+                    path,
+                    anchor,
+                    key: None,
+                    entries: Vec::new(),
+                });
+            }
+            Event::SequenceStart(anchor) => {
+                let path = self.enter(mark);
+                self.stack.push(Frame::Seq {
+                    path,
+                    anchor,
+                    items: Vec::new(),
+                });
+            }
+            Event::DocumentEnd => {
+                let value = self.root.take().unwrap_or_else(YamlValue::null);
+                self.documents.values.push(value);
+            }
+            Event::MappingEnd | Event::SequenceEnd => match self.stack.pop() {
+                Some(Frame::Map { anchor, entries, .. }) => self.leave(YamlValue::Map(entries), anchor),
+                Some(Frame::Seq { anchor, items, .. }) => self.leave(YamlValue::Seq(items), anchor),
+                None => {}
+            },
+            Event::Scalar(value, style, anchor, tag) => {
+                let key = match self.stack.last() {
+                    Some(Frame::Map { path, key: None, .. }) if path.is_empty() => Some(value.clone()),
+                    Some(Frame::Map { path, key: None, .. }) => Some(format!("{}.{}", path, value)),
+                    _ => None,
+                };
+                match key {
+                    Some(key) => {
+                        self.record(&key, mark);
+                        if let Some(Frame::Map { key: current, .. }) = self.stack.last_mut() {
+                            *current = Some((value, key));
+                        }
+                    }
+                    None => {
+                        self.enter(mark);
+                        let tagged_str =
+                            matches!(&tag, Some(TokenType::Tag(handle, suffix)) if handle == "!!" && suffix == "str");
+                        let plain = style == TScalarStyle::Plain && !tagged_str;
+                        self.leave(YamlValue::Scalar { text: value, plain }, anchor);
+                    }
+                }
+            }
+            Event::Alias(anchor) => {
+                self.enter(mark);
+                let value = self.anchors.get(&anchor).cloned().unwrap_or_else(YamlValue::null);
+                self.leave(value, 0);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The problems of a YAML config, as `config check` prints them
+    fn errors(yaml: &str) -> Vec<String> {
+        parse(yaml, "test.yaml", ConfigFormat::Yaml)
+            .errors
+            .iter()
+            .map(|err| err.to_string())
+            .collect()
+    }
+
+    fn http_source(endpoints: &str, extra: &str) -> String {
+        format!(
+            "server: cloudflare\ndomain: a.example.com\napi_token: t\nip_source:\n  type: http\n{}  endpoints:\n{}",
+            extra, endpoints
+        )
+    }
+
+    #[test]
+    fn checks_quorum_per_family() {
+        let two_each = concat!(
+            "    - {url: https://a, family: ipv4}\n",
+            "    - {url: https://b, family: ipv4}\n",
+            "    - {url: https://c, family: ipv6}\n",
+            "    - {url: https://d, family: ipv6}\n",
+        );
+        let shared = "    - https://a\n    - https://b\n    - {url: https://c, family: ipv6}\n";
+        // (endpoints, strategy and quorum, expected errors)
+        let cases: [(&str, &str, &[&str]); 7] = [
+            (two_each, "  strategy: consensus\n", &[]),
+            (two_each, "  strategy: consensus\n  quorum: 2\n", &[]),
+            (
+                two_each,
+                "  strategy: consensus\n  quorum: 3\n",
+                &["test.yaml:7 (document 1): ip_source.quorum: must be between 1 and the number of IPv4 endpoints (2), got 3"],
+            ),
+            (
+                two_each,
+                "  strategy: consensus\n  quorum: 0\n",
+                &["test.yaml:7 (document 1): ip_source.quorum: must be between 1 and the number of IPv4 endpoints (2), got 0"],
+            ),
+            // Endpoints without a family vote for both
+            (shared, "  strategy: consensus\n  quorum: 2\n", &[]),
+            (
+                shared,
+                "  strategy: consensus\n  quorum: 3\n",
+                &["test.yaml:7 (document 1): ip_source.quorum: must be between 1 and the number of IPv4 endpoints (2), got 3"],
+            ),
+            (
+                two_each,
+                "  quorum: 2\n",
+                &["test.yaml:6 (document 1): ip_source.quorum: only applies to strategy: consensus"],
+            ),
+        ];
+        for (endpoints, extra, expected) in cases {
+            assert_eq!(errors(&http_source(endpoints, extra)), expected, "{}", extra);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_stun_servers() {
+        let yaml = "server: cloudflare\ndomain: a.example.com\napi_token: t\nip_source:\n  type: stun\n  servers: []\n";
+        assert_eq!(
+            errors(yaml),
+            ["test.yaml:6 (document 1): ip_source.servers: must not be empty"]
+        );
+        let yaml = "server: cloudflare\ndomain: a.example.com\napi_token: t\nip_source:\n  type: stun\n  servers: [stun.example.com]\n";
+        assert!(errors(yaml).is_empty());
+    }
+
+    const VALID: &str = "server: cloudflare\ndomain: a.example.com\napi_token: t\n";
+
+    #[test]
+    fn reports_provider_errors() {
+        assert_eq!(
+            errors("domain: a.example.com\napi_token: t\n"),
+            ["test.yaml:1 (document 1): server: is required"]
+        );
+        // Any other server is a Mail-in-a-Box host
+        assert_eq!(
+            errors("server: box.example.com\ndomain: a.example.com\n"),
+            [
+                "test.yaml:1 (document 1): methods: is required (a list of POST, PUT or DELETE)",
+                "test.yaml:1 (document 1): password: is required",
+                "test.yaml:1 (document 1): username: is required",
+            ]
+        );
+        let google = errors("server: domains.google.com\ndomain: a.example.com\n");
+        assert_eq!(google.len(), 1);
+        assert!(
+            google[0].starts_with("test.yaml:1 (document 1): server: Google Domains DDNS (domains.google.com) is no longer supported."),
+            "{}",
+            google[0]
+        );
+    }
+
+    #[test]
+    fn reports_bad_ttl() {
+        for ttl in ["soon", "5", "2d"] {
+            assert_eq!(
+                errors(&format!("{}ttl: {}\n", VALID, ttl)),
+                [format!("test.yaml:4 (document 1): ttl: must be auto or between 30s and 1d (got '{}')", ttl)]
+            );
+        }
+        assert!(errors(&format!("{}ttl: 5m\n", VALID)).is_empty());
+    }
+
+    #[test]
+    fn reports_unknown_ip_source() {
+        assert_eq!(
+            errors(&format!("{}ip_source:\n  type: carrier-pigeon\n", VALID)),
+            ["test.yaml:5 (document 1): ip_source.type: must be http, interface, router, dns or stun (got 'carrier-pigeon')"]
+        );
+    }
+
+    #[test]
+    fn reports_missing_domain() {
+        assert_eq!(
+            errors("# comment\nserver: cloudflare\napi_token: t\n"),
+            ["test.yaml:2 (document 1): domain: is required"]
+        );
+    }
+
+    #[test]
+    fn reports_document_line_and_field() {
+        let yaml = format!("{}---\n\n{}records: [A, MX]\n", VALID, VALID);
+        let config = parse(&yaml, "test.yaml", ConfigFormat::Yaml);
+        assert_eq!(config.documents, 2);
+        assert_eq!(config.entries.len(), 1);
+        assert_eq!(config.invalid, 1);
+        let error = &config.errors[0];
+        assert_eq!(
+            (error.file.as_str(), error.document, error.line, error.field.as_deref()),
+            ("test.yaml", Some(2), Some(9), Some("records[1]"))
+        );
+        assert_eq!(error.message, "must be A or AAAA (got 'MX')");
+
+        // Type errors are located too; a field expecting a string reads plain scalars as written
+        assert_eq!(
+            errors(&format!("{}ip_source:\n  type: http\n  quorum: many\n", VALID)),
+            ["test.yaml:6 (document 1): ip_source.quorum: invalid type: string \"many\", expected i64"]
+        );
+        assert!(errors("server: cloudflare\ndomain: a.example.com\napi_token: 12345\n").is_empty());
+        let aliased = "server: cloudflare\ndomain: &host a.example.com\napi_token: t\ndomains: [*host]\n";
+        assert_eq!(
+            errors(aliased),
+            ["test.yaml:2 (document 1): domain: cannot be combined with domains; list every domain under domains"]
+        );
+        assert_eq!(
+            errors(&format!("{}domain: b.example.com\n", VALID)),
+            ["test.yaml:1 (document 1): duplicate field `domain`"]
+        );
+        assert_eq!(
+            errors(&format!("{}---\ndomain: [\n", VALID)),
+            ["test.yaml:6 (document 2): invalid YAML: while parsing a node, did not find expected node content"]
+        );
+    }
+
+    #[test]
+    fn parses_every_format() {
+        let yaml = "server: cloudflare\ndomain: a.example.com\napi_token: &token 't'\n---\nserver: cloudflare\ndomain: b.example.com\napi_token: t\n";
+        let json = r#"[{"server": "cloudflare", "domain": "a.example.com", "api_token": "t"},
+            {"server": "cloudflare", "domain": "b.example.com", "api_token": "t", "ttl": "5m"}]"#;
+        let toml = "[[entries]]\nserver = \"cloudflare\"\ndomain = \"a.example.com\"\napi_token = \"t\"\n\n[[entries]]\nserver = \"cloudflare\"\ndomain = \"b.example.com\"\napi_token = \"t\"\n";
+        for (contents, format) in [(yaml, ConfigFormat::Yaml), (json, ConfigFormat::Json), (toml, ConfigFormat::Toml)] {
+            let config = parse(contents, "test", format);
+            assert!(config.errors.is_empty(), "{:?}", config.errors);
+            let domains: Vec<&str> = config.entries.iter().map(|entry| entry.domain.as_str()).collect();
+            assert_eq!(domains, ["a.example.com", "b.example.com"]);
+            assert!(config
+                .entries
+                .iter()
+                .all(|entry| matches!(&entry.provider, Provider::Cloudflare { api_token, .. } if api_token == "t")));
+        }
+
+        let json = r#"[{"server": "cloudflare", "domain": "a.example.com", "api_token": "t"},
+{"server": "cloudflare", "api_token": "t"}]"#;
+        let config = parse(json, "test.json", ConfigFormat::Json);
+        assert_eq!(config.errors[0].to_string(), "test.json:2 (document 2): domain: is required");
+        let toml = "[[entries]]\nserver = \"cloudflare\"\napi_token = \"t\"\n";
+        let config = parse(toml, "test.toml", ConfigFormat::Toml);
+        assert_eq!(config.errors[0].to_string(), "test.toml (document 1): domain: is required");
+    }
+
+    #[test]
+    fn skips_invalid_entries_on_request() {
+        let valid = parse(VALID, "test.yaml", ConfigFormat::Yaml);
+        assert_eq!(valid.accept(false), Ok(None));
+
+        let mixed = parse(&format!("{}---\nserver: cloudflare\napi_token: t\n", VALID), "test.yaml", ConfigFormat::Yaml);
+        let problems = "(1 problem):\n  test.yaml:5 (document 2): domain: is required";
+        assert_eq!(
+            mixed.accept(false),
+            Err(format!(
+                "Invalid config {}\nFix them, or pass --skip-invalid to run only the valid entries",
+                problems
+            ))
+        );
+        assert_eq!(
+            mixed.accept(true),
+            Ok(Some(format!("Skipping invalid config entries {}", problems)))
+        );
+
+        // Without a valid entry there is nothing to run
+        let invalid = parse("server: cloudflare\napi_token: t\n", "test.yaml", ConfigFormat::Yaml);
+        assert!(invalid.accept(true).is_err());
+    }
+
+    /// Domain, records and provider of each entry
+    fn summaries(config: &Config) -> Vec<String> {
+        config
+            .entries
+            .iter()
+            .map(|entry| format!("{} {:?} {:?}", entry.domain, entry.records, entry.provider))
+            .collect()
+    }
+
+    #[test]
+    fn applies_defaults_until_overridden() {
+        let yaml = concat!(
+            "defaults:\n  server: cloudflare\n  api_token: t\n  records: [A, AAAA]\n  ttl: 300\n",
+            "---\ndomain: a.example.com\n",
+            "---\ndomain: b.example.com\nrecords: [A]\nttl: 60\n",
+            "---\nserver: namecheap\ndomain: c.example.com\npassword: p\nrecords: [A]\n",
+            "---\ndefaults:\n  server: cloudflare\n  api_token: u\n",
+            "---\ndomain: d.example.com\n",
+        );
+        let config = parse(yaml, "test.yaml", ConfigFormat::Yaml);
+        assert_eq!(config.errors.len(), 0);
+        // The default TTL does not apply to Namecheap, and is not worth a warning there
+        assert_eq!(config.warnings.len(), 0);
+        assert_eq!(
+            summaries(&config),
+            [
+                r#"a.example.com [A, Aaaa] Cloudflare { api_token: "t", ttl: Some(300) }"#,
+                r#"b.example.com [A] Cloudflare { api_token: "t", ttl: Some(60) }"#,
+                r#"c.example.com [A] Namecheap { password: "p" }"#,
+                // A later defaults document replaces the earlier one as a whole
+                r#"d.example.com [A] Cloudflare { api_token: "u", ttl: None }"#,
+            ]
+        );
+        assert_eq!(config.defaults, [("test.yaml".to_string(), 1), ("test.yaml".to_string(), 5)]);
+    }
+
+    #[test]
+    fn expands_domains_to_one_entry_each() {
+        let yaml = concat!(
+            "defaults:\n  server: cloudflare\n  api_token: t\n  records: [A, AAAA]\n",
+            "---\ndomains:\n",
+            "  - a.example.com\n",
+            "  - domain: b.example.com\n    records: [AAAA]\n    ttl: 120\n",
+            "  - domain: c.example.com\n    records: [MX]\n",
+            "  - d.example.com\n",
+        );
+        let config = parse(yaml, "test.yaml", ConfigFormat::Yaml);
+        assert_eq!(
+            summaries(&config),
+            [
+                r#"a.example.com [A, Aaaa] Cloudflare { api_token: "t", ttl: None }"#,
+                r#"b.example.com [Aaaa] Cloudflare { api_token: "t", ttl: Some(120) }"#,
+                r#"d.example.com [A, Aaaa] Cloudflare { api_token: "t", ttl: None }"#,
+            ]
+        );
+        assert!(config.entries.iter().all(|entry| entry.document == 2));
+        // An invalid item leaves out only its own domain
+        assert_eq!(config.invalid, 1);
+        assert_eq!(
+            config.errors[0].to_string(),
+            "test.yaml:12 (document 2): domains[2].records[0]: must be A or AAAA (got 'MX')"
+        );
+    }
+
+    #[test]
+    fn reports_conflicting_keys() {
+        let yaml = concat!(
+            "server: cloudflare\napi_token: t\ndomain: a.example.com\ndomains: [b.example.com]\n",
+            "---\ndefaults:\n  server: cloudflare\n  domain: a.example.com\n",
+            "---\nserver: cloudflare\ndefaults:\n  api_token: t\n",
+            "---\ninclude: other.yaml\ndomain: a.example.com\n",
+            "---\nserver: cloudflare\napi_token: t\ndomains:\n",
+            "  - domain: a.example.com\n    ttl: soon\n",
+            "  - domain: b.example.com\n    port: 1\n",
+        );
+        assert_eq!(
+            errors(yaml),
+            [
+                "test.yaml:3 (document 1): domain: cannot be combined with domains; list every domain under domains",
+                "test.yaml:8 (document 2): defaults.domain: cannot be a default",
+                "test.yaml:10 (document 3): server: cannot be combined with defaults; put the entry in a document of its own",
+                "test.yaml:15 (document 4): domain: cannot be combined with include; put the entry in a document of its own",
+                "test.yaml:21 (document 5): domains[0].ttl: must be auto or between 30s and 1d (got 'soon')",
+                "test.yaml:23 (document 5): domains[1].port: unknown field",
+            ]
+        );
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-ddns-config-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(domain: &str) -> String {
+        format!("server: cloudflare\ndomain: {}\napi_token: t\n", domain)
+    }
+
+    /// The problems of a loaded config, with paths relative to `dir`
+    fn relative(problems: &[ConfigError], dir: &Path) -> Vec<String> {
+        let prefix = format!("{}/", dir.display());
+        problems.iter().map(|err| err.to_string().replace(&prefix, "")).collect()
+    }
+
+    #[test]
+    fn loads_includes_and_directories_in_order() {
+        let dir = temp_dir("includes");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(dir.join("ddns.yaml"), format!("include: sub/inner.yaml\n---\n{}", entry("main.example.com"))).unwrap();
+        // Relative to the including file, not the working directory or the top file
+        fs::write(dir.join("sub/inner.yaml"), format!("{}---\ninclude: ../conf.d\n", entry("inner.example.com"))).unwrap();
+        fs::write(dir.join("conf.d/20-second.yaml"), entry("second.example.com")).unwrap();
+        fs::write(dir.join("conf.d/10-first.conf"), entry("first.example.com")).unwrap();
+        fs::write(dir.join("conf.d/.hidden.yaml"), entry("hidden.example.com")).unwrap();
+        fs::write(dir.join("conf.d/notes.txt"), "not a config").unwrap();
+
+        let config = load(&dir.join("ddns.yaml").display().to_string(), None, false).unwrap();
+        assert_eq!(relative(&config.errors, &dir), Vec::<String>::new());
+        let domains: Vec<_> = config.entries.iter().map(|entry| entry.domain.as_str()).collect();
+        assert_eq!(
+            domains,
+            ["main.example.com", "inner.example.com", "first.example.com", "second.example.com"]
+        );
+        let path = |name: &str| dir.join(name).display().to_string();
+        assert_eq!(
+            config.files,
+            [
+                path("ddns.yaml"),
+                path("sub/inner.yaml"),
+                path("conf.d/10-first.conf"),
+                path("conf.d/20-second.yaml")
+            ]
+        );
+        assert_eq!(config.directories, [path("conf.d")]);
+        assert_eq!(config.entries[3].file, path("conf.d/20-second.yaml"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_each_file_once() {
+        let dir = temp_dir("cycle");
+        fs::write(dir.join("a.yaml"), format!("include: [b.yaml, ./b.yaml, link.yaml]\n---\n{}", entry("a.example.com"))).unwrap();
+        fs::write(dir.join("b.yaml"), format!("include: a.yaml\n---\n{}", entry("b.example.com"))).unwrap();
+        std::os::unix::fs::symlink("b.yaml", dir.join("link.yaml")).unwrap();
+
+        let config = load(&dir.join("a.yaml").display().to_string(), None, false).unwrap();
+        let domains: Vec<_> = config.entries.iter().map(|entry| entry.domain.as_str()).collect();
+        assert_eq!(domains, ["a.example.com", "b.example.com"]);
+        assert_eq!(config.errors.len(), 0);
+        assert_eq!(
+            relative(&config.warnings, &dir),
+            [
+                "a.yaml: is already loaded; skipped",
+                "b.yaml: is already loaded; skipped",
+                "link.yaml: is already loaded; skipped"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn attributes_errors_to_their_file() {
+        let dir = temp_dir("errors");
+        fs::write(dir.join("ddns.yaml"), format!("{}---\ninclude:\n  - bad.yaml\n  - missing.yaml\n", entry("a.example.com"))).unwrap();
+        fs::write(dir.join("bad.yaml"), "server: cloudflare\napi_token: t\n").unwrap();
+
+        let config = load(&dir.join("ddns.yaml").display().to_string(), None, false).unwrap();
+        assert_eq!(config.entries.len(), 1);
+        let errors = relative(&config.errors, &dir);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], "bad.yaml:1 (document 1): domain: is required");
+        // An include that cannot be read is a problem of the document naming it
+        assert!(
+            errors[1].starts_with("ddns.yaml:7 (document 2): include[1]: missing.yaml: could not read config file: "),
+            "{}",
+            errors[1]
+        );
+
+        // Only the top path failing to load is an error of its own
+        let missing = dir.join("nothing.yaml").display().to_string();
+        assert_eq!(load(&missing, None, false).unwrap_err().file, missing);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn resolves_env_secrets() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(resolve_secret("env:PATH"), Ok((path, None)));
+        assert_eq!(
+            resolve_secret("env:RUST_DDNS_TEST_UNSET_VARIABLE"),
+            Err("env var 'RUST_DDNS_TEST_UNSET_VARIABLE' is not set".to_string())
+        );
+        assert_eq!(resolve_secret(" literal "), Ok((" literal ".to_string(), None)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_file_secrets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("secrets");
+        let file = |name: &str, contents: &str, mode: u32| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            format!("file:{}", path.display())
+        };
+
+        assert_eq!(resolve_secret(&file("private", "  t0ken\n", 0o600)), Ok(("t0ken".to_string(), None)));
+        assert_eq!(resolve_secret(&file("empty", "\n", 0o600)), Err("file: secret is empty".to_string()));
+        let missing = resolve_secret(&format!("file:{}", dir.join("missing").display())).unwrap_err();
+        assert!(missing.starts_with("could not read secret file"), "{}", missing);
+
+        // Readable by others, but still used; only the warning says why that is a problem
+        let (secret, warning) = resolve_secret(&file("shared", "t0ken\n", 0o644)).unwrap();
+        assert_eq!(secret, "t0ken");
+        let warning = warning.unwrap();
+        assert!(warning.contains("(mode 644), so other local users can read the secret"), "{}", warning);
+        assert!(warning.ends_with("restrict it with chmod 600"), "{}", warning);
+
+        // A read-only file is only trusted to be a managed secret when root owns it
+        let (_, warning) = resolve_secret(&file("mounted", "t0ken\n", 0o444)).unwrap();
+        assert_eq!(warning.is_none(), unsafe { libc::geteuid() } == 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn warns_about_a_shared_secret_file_once() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("secret-warning");
+        let path = dir.join("token");
+        fs::write(&path, "t0ken\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let entry = |domain: &str| {
+            format!("server: cloudflare\ndomain: {}\napi_token: file:{}\n", domain, path.display())
+        };
+        let yaml = format!("{}---\n{}", entry("a.example.com"), entry("b.example.com"));
+        let config = parse(&yaml, "test.yaml", ConfigFormat::Yaml);
+        assert_eq!(config.entries.len(), 2);
+        let warnings: Vec<_> = config.warnings.iter().map(|warning| warning.to_string()).collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("test.yaml:3 (document 1): api_token: secret file"), "{}", warnings[0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_command_secrets() {
+        assert_eq!(resolve_secret("cmd:echo '  t0ken '"), Ok(("t0ken".to_string(), None)));
+        assert_eq!(resolve_secret("cmd:true"), Err("cmd: secret is empty".to_string()));
+        assert_eq!(resolve_secret("cmd:   "), Err("cmd: must be followed by a command".to_string()));
+
+        // Errors name the program and its stderr, never the rest of the command line
+        let failed = resolve_secret("cmd:sh -c 'echo denied >&2; exit 3' --token hunter2").unwrap_err();
+        assert_eq!(failed, "secret command 'sh' failed (exit status: 3): denied");
+        let missing = resolve_secret("cmd:rust-ddns-missing-program --token hunter2").unwrap_err();
+        assert!(missing.starts_with("secret command 'rust-ddns-missing-program' failed"), "{}", missing);
+        assert!(!missing.contains("hunter2"), "{}", missing);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stops_slow_secret_commands() {
+        let started = Instant::now();
+        assert_eq!(
+            run_secret_command("sleep 10; echo hunter2", Duration::from_millis(100)),
+            Err("secret command 'sleep' did not finish within 100ms".to_string())
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
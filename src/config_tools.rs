@@ -0,0 +1,605 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::arg_parser::ConfigFormat;
+use crate::config::{self, ConfigError, Entry, Provider};
+
+/// Validates the config at `path` and prints a report per document, without contacting
+/// any IP service or provider. `env:` and `file:` secrets are read, so unset variables and
+/// unreadable files are reported, but `cmd:` commands are not run. Documents of other files
+/// than `path` itself, from a config directory or an `include`, are labelled with their
+/// file.
+///
+/// Returns whether every entry is valid.
+pub fn check(path: &str, format: Option<ConfigFormat>) -> bool {
+    println!("Checking {}", path);
+    let config = match config::load(path, format, false) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("  {}", err);
+            return false;
+        }
+    };
+
+    // Problems with a file as a whole, such as a JSON syntax error or an unreadable file
+    let general: Vec<&ConfigError> = config.errors.iter().filter(|err| err.document.is_none()).collect();
+    for error in &general {
+        println!("  error: {}", error);
+    }
+    for warning in config.warnings.iter().filter(|warning| warning.document.is_none()) {
+        println!("  warning: {}", warning);
+    }
+
+    for file in &config.files {
+        let numbers: BTreeSet<usize> = config
+            .entries
+            .iter()
+            .filter(|entry| entry.file == *file)
+            .map(|entry| entry.document)
+            .chain(
+                config
+                    .errors
+                    .iter()
+                    .chain(&config.warnings)
+                    .filter(|problem| problem.file == *file)
+                    .filter_map(|problem| problem.document),
+            )
+            .chain(config.includes.iter().filter(|include| include.file == *file).map(|include| include.document))
+            .chain(config.defaults.iter().filter(|(of, _)| of == file).map(|(_, document)| *document))
+            .collect();
+        for document in numbers {
+            let of_document = |problems: &[ConfigError]| -> Vec<String> {
+                problems
+                    .iter()
+                    .filter(|problem| problem.file == *file && problem.document == Some(document))
+                    .map(describe_problem)
+                    .collect()
+            };
+            let errors = of_document(&config.errors);
+            let label = match file == path {
+                true => format!("document {}", document),
+                false => format!("{} document {}", file, document),
+            };
+            // A document with `domains` has an entry per domain
+            let entries = config.entries.iter().filter(|entry| entry.file == *file && entry.document == document);
+            let includes: Vec<&str> = config
+                .includes
+                .iter()
+                .filter(|include| include.file == *file && include.document == document)
+                .map(|include| include.path.as_str())
+                .collect();
+            for entry in entries {
+                println!("  {}: ok       {}", label, describe_entry(entry));
+            }
+            if !includes.is_empty() {
+                println!("  {}: include  {}", label, includes.join(", "));
+            } else if config.defaults.contains(&(file.clone(), document)) {
+                println!("  {}: defaults", label);
+            } else if !errors.is_empty() {
+                println!("  {}: INVALID", label);
+            }
+            for error in errors {
+                println!("      error: {}", error);
+            }
+            for warning in of_document(&config.warnings) {
+                println!("      warning: {}", warning);
+            }
+        }
+    }
+
+    let entries = config.entries.len() + config.invalid;
+    println!(
+        "{} {}: {} valid, {} invalid, {} {}",
+        entries,
+        plural(entries, "entry", "entries"),
+        config.entries.len(),
+        config.invalid,
+        config.warnings.len(),
+        plural(config.warnings.len(), "warning", "warnings"),
+    );
+    config.errors.is_empty()
+}
+
+/// One line describing what an entry will do, e.g.
+/// `cloudflare home.example.com A, AAAA (ip_source: http, compare: dns)`.
+fn describe_entry(entry: &Entry) -> String {
+    let records: Vec<String> = entry.records.iter().map(|record| record.to_string()).collect();
+    let mut description = format!(
+        "{} {} {} (ip_source: {}",
+        entry.provider.server(),
+        entry.domain,
+        records.join(", "),
+        entry.ip_source
+    );
+    match &entry.provider {
+        Provider::MailInABox { methods, .. } => {
+            let methods: Vec<String> = methods.iter().map(|method| method.to_string()).collect();
+            description.push_str(&format!(", methods: {}", methods.join(", ")));
+        }
+        Provider::Cloudflare { ttl: Some(1), .. } => description.push_str(", ttl: auto"),
+        Provider::Cloudflare { ttl: Some(ttl), .. } => description.push_str(&format!(", ttl: {}s", ttl)),
+        _ => {}
+    }
+    match &entry.comparison {
+        Some(comparison) => description.push_str(&format!(", compare: {}", comparison)),
+        None => description.push_str(", compare: dns"),
+    }
+    if let Some(max_age) = entry.max_age {
+        description.push_str(&format!(", max_age: {}s", max_age));
+    }
+    if !entry.nameservers.is_empty() {
+        let nameservers: Vec<String> = entry.nameservers.iter().map(|ns| ns.to_string()).collect();
+        description.push_str(&format!(", nameservers: {}", nameservers.join(", ")));
+    }
+    description.push(')');
+    description
+}
+
+/// A problem without the file and document, which the report already shows.
+fn describe_problem(problem: &ConfigError) -> String {
+    let mut description = String::new();
+    if let Some(line) = problem.line {
+        description.push_str(&format!("line {}: ", line));
+    }
+    if let Some(field) = &problem.field {
+        description.push_str(&format!("{}: ", field));
+    }
+    description.push_str(&problem.message);
+    description
+}
+
+fn plural<'a>(count: usize, one: &'a str, many: &'a str) -> &'a str {
+    if count == 1 {
+        one
+    } else {
+        many
+    }
+}
+
+/// The providers the wizard can set up, in menu order
+const PROVIDERS: [&str; 3] = ["Cloudflare", "Namecheap", "Mail-in-a-Box"];
+
+/// Asks for one entry on stdin and writes it to the file at `path` as a YAML document, or
+/// appends it as a new `---` document if the file already has entries. If `path` is a
+/// config directory, the entry goes to `<domain>.conf` inside it. The file is left readable
+/// by its owner only, since it may now hold a secret. Only YAML files can be written.
+pub fn init(path: &str, format: Option<ConfigFormat>) -> io::Result<()> {
+    let directory = Path::new(path).is_dir();
+    let format = match (format, directory) {
+        (Some(format), _) => format,
+        (None, true) => ConfigFormat::Yaml,
+        (None, false) => config::detect_format(path),
+    };
+    if format != ConfigFormat::Yaml {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("only YAML config files can be written, not {:?}", format),
+        ));
+    }
+    if directory {
+        println!("Adding a file to {}", path);
+    } else if read_existing(path)?.trim().is_empty() {
+        println!("Creating {}", path);
+    } else {
+        println!("Adding an entry to {}", path);
+    }
+
+    let provider = choose("DNS provider", &PROVIDERS, 0)?;
+    let server = match provider {
+        0 => "cloudflare".to_string(),
+        1 => "namecheap".to_string(),
+        _ => ask("Mail-in-a-Box server, e.g. box.example.com", None, valid_hostname)?,
+    };
+    let domain = ask("Domain to update, e.g. home.example.com", None, valid_hostname)?;
+    let file = match directory {
+        true => Path::new(path).join(format!("{}.conf", domain)).display().to_string(),
+        false => path.to_string(),
+    };
+    let records = if provider == 1 {
+        println!("Namecheap DDNS only supports A records");
+        "A".to_string()
+    } else {
+        ["A", "AAAA", "A, AAAA"][choose("Record types", &["A (IPv4)", "AAAA (IPv6)", "A and AAAA"], 0)?]
+            .to_string()
+    };
+
+    let (secret_field, secret_name, default_var) = match provider {
+        0 => ("api_token", "Cloudflare API token", "CF_API_TOKEN"),
+        1 => ("password", "Namecheap DDNS password", "NAMECHEAP_DDNS_PASSWORD"),
+        _ => ("password", "Mail-in-a-Box password", "MIAB_PASSWORD"),
+    };
+    let mailinabox = if provider == 2 {
+        let username = ask("Mail-in-a-Box username (email address)", None, |value| {
+            (!value.is_empty()).then_some(()).ok_or("must not be empty")
+        })?;
+        let methods = ask("HTTP methods to send, comma separated", Some("PUT"), |value| {
+            value
+                .split(',')
+                .all(|method| matches!(method.trim().to_uppercase().as_str(), "PUT" | "POST" | "DELETE"))
+                .then_some(())
+                .ok_or("must be PUT, POST or DELETE")
+        })?;
+        Some((username, methods))
+    } else {
+        None
+    };
+
+    let storage = choose(
+        &format!("How should the {} be stored", secret_name),
+        &["Environment variable (env: reference, recommended)", "Inline in the config file"],
+        0,
+    )?;
+    let secret = if storage == 0 {
+        let var = ask("Environment variable name", Some(default_var), |value| {
+            let mut chars = value.chars();
+            let first = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+            (first && chars.all(|c| c.is_ascii_alphanumeric() || c == '_'))
+                .then_some(())
+                .ok_or("must be letters, digits and underscores, not starting with a digit")
+        })?;
+        Secret::Env(var)
+    } else {
+        let secret = read_secret(&format!("{}: ", secret_name))?;
+        if let Err(err) = inline_secret(&secret) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the {} {}", secret_name, err)));
+        }
+        Secret::Inline(secret)
+    };
+    let answers = Answers {
+        server,
+        domain,
+        records,
+        mailinabox,
+        secret_field,
+        secret,
+    };
+    let document = answers.document();
+    let env_var = match answers.secret {
+        Secret::Env(var) => Some(var),
+        Secret::Inline(_) => None,
+    };
+
+    // The wizard only writes what it was given, so this catches its own mistakes; an
+    // `env:` secret whose variable is not set yet in this shell is fine
+    let problems: Vec<String> = config::parse(&document, &file, ConfigFormat::Yaml)
+        .errors
+        .iter()
+        .filter(|err| env_var.is_none() || err.field.as_deref() != Some(secret_field))
+        .map(|err| err.to_string())
+        .collect();
+    if !problems.is_empty() {
+        return Err(io::Error::other(format!("the new entry is invalid: {}", problems.join("; "))));
+    }
+
+    let existing = read_existing(&file)?;
+    if !existing.trim().is_empty() && !confirm(&format!("Append this entry to {}?", file))? {
+        println!("Nothing written");
+        return Ok(());
+    }
+    let document_number = write_document(&file, &existing, &document)?;
+    println!("Wrote document {} to {}", document_number, file);
+    if let Some(var) = env_var {
+        if std::env::var(&var).is_err() {
+            println!(
+                "Set {} in the environment of rust-ddns before it runs, e.g. with a systemd EnvironmentFile",
+                var
+            );
+        }
+    }
+    println!("Run `rust-ddns{} config check` to validate it", config_flag(path));
+    Ok(())
+}
+
+/// Where the wizard stores the secret
+enum Secret {
+    /// An `env:` reference to this variable
+    Env(String),
+    /// The secret itself
+    Inline(String),
+}
+
+/// The entry the wizard asked for
+struct Answers {
+    server: String,
+    domain: String,
+    /// `A`, `AAAA` or `A, AAAA`
+    records: String,
+    /// The username and comma separated methods for Mail-in-a-Box
+    mailinabox: Option<(String, String)>,
+    secret_field: &'static str,
+    secret: Secret,
+}
+
+impl Answers {
+    /// The entry as a YAML document.
+    fn document(&self) -> String {
+        let mut document = format!("server: {}\ndomain: {}\nrecords:\n", scalar(&self.server), scalar(&self.domain));
+        for record in self.records.split(", ") {
+            document.push_str(&format!("    - {}\n", record));
+        }
+        if let Some((username, methods)) = &self.mailinabox {
+            document.push_str(&format!("username: {}\n", scalar(username)));
+            document.push_str("methods:\n");
+            for method in methods.split(',') {
+                document.push_str(&format!("    - {}\n", method.trim().to_uppercase()));
+            }
+        }
+        match &self.secret {
+            Secret::Env(var) => document.push_str(&format!("{}: env:{}\n", self.secret_field, var)),
+            Secret::Inline(secret) => document.push_str(&format!("{}: {}\n", self.secret_field, scalar(secret))),
+        }
+        document
+    }
+}
+
+/// Checks a secret to be written inline. The config has no way to escape a value that
+/// starts like a reference, so such a secret would be read as `env:`, `file:` or `cmd:`.
+fn inline_secret(secret: &str) -> Result<(), &'static str> {
+    if secret.is_empty() {
+        return Err("must not be empty");
+    }
+    if ["env:", "file:", "cmd:"].iter().any(|prefix| secret.starts_with(prefix)) {
+        return Err("starts with env:, file: or cmd: and would be read as a reference; store it in an environment variable instead");
+    }
+    Ok(())
+}
+
+/// The contents of `file`, or nothing if it does not exist yet.
+fn read_existing(file: &str) -> io::Result<String> {
+    match fs::read_to_string(file) {
+        Ok(contents) => Ok(contents),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Appends `document` to `file`, after a `---` separator if it already has content, and
+/// restricts the file to its owner. Returns the number of the new document.
+fn write_document(file: &str, existing: &str, document: &str) -> io::Result<usize> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut out = options.open(file)?;
+    // An existing file keeps its mode on open, so tighten it as well
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(file, fs::Permissions::from_mode(0o600))?;
+    }
+
+    let mut text = String::new();
+    let mut number = 1;
+    if !existing.trim().is_empty() {
+        number = config::parse(existing, file, ConfigFormat::Yaml).documents + 1;
+        if !existing.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str("---\n");
+    }
+    text.push_str(document);
+    out.write_all(text.as_bytes())?;
+    Ok(number)
+}
+
+/// The `--config-file` option to repeat in suggested commands, if `file` is not the default.
+fn config_flag(file: &str) -> String {
+    if file == crate::api_client::get_config_file_path(None) {
+        String::new()
+    } else {
+        format!(" --config-file {}", file)
+    }
+}
+
+/// Quotes `value` for YAML if it would not otherwise read back as the same string.
+fn scalar(value: &str) -> String {
+    let mut yaml = String::new();
+    match yaml_rust::YamlEmitter::new(&mut yaml).dump(&yaml_rust::Yaml::String(value.to_string())) {
+        Ok(()) => yaml.trim_start_matches("---").trim().to_string(),
+        Err(_) => format!("{:?}", value),
+    }
+}
+
+fn valid_hostname(value: &str) -> Result<(), &'static str> {
+    let valid = value.contains('.')
+        && value
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    valid.then_some(()).ok_or("must be a host name such as home.example.com")
+}
+
+/// Reads one trimmed line from stdin, failing if the input has ended.
+fn read_line() -> io::Result<String> {
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input ended before the entry was complete"));
+    }
+    Ok(line.trim().to_string())
+}
+
+/// Asks until the answer passes `check`; an empty answer takes `default`, if there is one.
+fn ask(question: &str, default: Option<&str>, check: impl Fn(&str) -> Result<(), &'static str>) -> io::Result<String> {
+    loop {
+        match default {
+            Some(default) => print!("{} [{}]: ", question, default),
+            None => print!("{}: ", question),
+        }
+        let mut answer = read_line()?;
+        if answer.is_empty() {
+            answer = default.unwrap_or_default().to_string();
+        }
+        match check(&answer) {
+            Ok(()) => return Ok(answer),
+            Err(reason) => println!("  {}", reason),
+        }
+    }
+}
+
+/// Asks for one of `options` by number and returns its index.
+fn choose(question: &str, options: &[&str], default: usize) -> io::Result<usize> {
+    println!("{}:", question);
+    for (i, option) in options.iter().enumerate() {
+        println!("  {}) {}", i + 1, option);
+    }
+    let default = (default + 1).to_string();
+    let answer = ask("Choice", Some(&default), |value| match value.parse::<usize>() {
+        Ok(n) if (1..=options.len()).contains(&n) => Ok(()),
+        _ => Err("enter one of the numbers above"),
+    })?;
+    Ok(answer.parse::<usize>().map(|n| n - 1).unwrap_or_default())
+}
+
+fn confirm(question: &str) -> io::Result<bool> {
+    loop {
+        print!("{} [Y/n]: ", question);
+        match read_line()?.to_lowercase().as_str() {
+            "" | "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("  answer y or n"),
+        }
+    }
+}
+
+/// Reads a secret without echoing it when stdin is a terminal.
+#[cfg(unix)]
+fn read_secret(prompt: &str) -> io::Result<String> {
+    print!("{}", prompt);
+    let fd = libc::STDIN_FILENO;
+    // SAFETY: tcgetattr only writes to the termios it is given, and fails on a non-terminal
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+        return read_line();
+    }
+    let mut silent = original;
+    silent.c_lflag &= !libc::ECHO;
+    silent.c_lflag |= libc::ECHONL;
+    // SAFETY: both termios values came from tcgetattr on the same descriptor
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    let secret = read_line();
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    secret
+}
+
+#[cfg(not(unix))]
+fn read_secret(prompt: &str) -> io::Result<String> {
+    print!("{}", prompt);
+    read_line()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answers(server: &str, secret: Secret) -> Answers {
+        let mailinabox = !["cloudflare", "namecheap"].contains(&server);
+        Answers {
+            server: server.to_string(),
+            domain: "home.example.com".to_string(),
+            records: if server == "namecheap" { "A" } else { "A, AAAA" }.to_string(),
+            mailinabox: mailinabox.then(|| ("admin@example.com".to_string(), "put, post".to_string())),
+            secret_field: if server == "cloudflare" { "api_token" } else { "password" },
+            secret,
+        }
+    }
+
+    /// A directory of its own for one test
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-ddns-config-tools-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn writes_documents_that_parse() {
+        // Secrets YAML would otherwise read as a number, a mapping or a comment
+        for secret in ["s3cret", "12345", "a: b", "#hash", "'quoted'", "true"] {
+            for server in ["cloudflare", "namecheap", "box.example.com"] {
+                let document = answers(server, Secret::Inline(secret.to_string())).document();
+                let config = config::parse(&document, "new.yaml", ConfigFormat::Yaml);
+                assert!(config.errors.is_empty(), "{}: {:?}", document, config.errors);
+                assert!(config.warnings.is_empty(), "{}: {:?}", document, config.warnings);
+                let entry = &config.entries[0];
+                assert_eq!(entry.domain, "home.example.com");
+                let written = match &entry.provider {
+                    Provider::Cloudflare { api_token, .. } => api_token,
+                    Provider::Namecheap { password } => password,
+                    Provider::MailInABox { username, password, methods, .. } => {
+                        assert_eq!(username, "admin@example.com");
+                        assert_eq!(methods.len(), 2);
+                        password
+                    }
+                };
+                assert_eq!(written, secret);
+            }
+        }
+    }
+
+    #[test]
+    fn writes_env_references() {
+        let var = format!("RUST_DDNS_TEST_UNSET_{}", std::process::id());
+        let document = answers("cloudflare", Secret::Env(var.clone())).document();
+        assert!(document.ends_with(&format!("api_token: env:{}\n", var)));
+        // Only the variable, which need not be set while the wizard runs, is a problem
+        let errors = config::parse(&document, "new.yaml", ConfigFormat::Yaml).errors;
+        let fields: Vec<Option<&str>> = errors.iter().map(|err| err.field.as_deref()).collect();
+        assert_eq!(fields, [Some("api_token")]);
+    }
+
+    #[test]
+    fn rejects_inline_secrets_that_look_like_references() {
+        assert!(inline_secret("s3cret").is_ok());
+        assert!(inline_secret("token:env:x").is_ok());
+        assert_eq!(inline_secret(""), Err("must not be empty"));
+        for secret in ["env:HOME", "file:/etc/passwd", "cmd:id"] {
+            assert!(inline_secret(secret).is_err(), "{}", secret);
+        }
+    }
+
+    #[test]
+    fn appends_documents() {
+        let dir = temp_dir("append");
+        let file = dir.join("ddns.yaml").display().to_string();
+        let first = answers("cloudflare", Secret::Inline("one".to_string())).document();
+        let second = answers("namecheap", Secret::Inline("two".to_string())).document();
+
+        assert_eq!(write_document(&file, "", &first).unwrap(), 1);
+        let existing = fs::read_to_string(&file).unwrap();
+        assert_eq!(existing, first);
+        assert_eq!(write_document(&file, &existing, &second).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&file).unwrap(), format!("{}---\n{}", first, second));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert!(check(&file, None));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_files() {
+        let dir = temp_dir("check");
+        let valid = dir.join("valid.yaml");
+        fs::write(&valid, "server: cloudflare\ndomain: a.example.com\napi_token: t\n").unwrap();
+        assert!(check(valid.to_str().unwrap(), None));
+
+        let invalid = dir.join("invalid.yaml");
+        fs::write(&invalid, "server: cloudflare\ndomain: a.example.com\napi_token: t\n---\nserver: cloudflare\n").unwrap();
+        assert!(!check(invalid.to_str().unwrap(), None));
+        assert!(!check(dir.join("missing.yaml").to_str().unwrap(), None));
+        // A `cmd:` secret is only checked to name a command, not run
+        let command = dir.join("cmd.yaml");
+        fs::write(&command, "server: cloudflare\ndomain: a.example.com\napi_token: 'cmd:exit 1'\n").unwrap();
+        assert!(check(command.to_str().unwrap(), None));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
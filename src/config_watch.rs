@@ -0,0 +1,238 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Watches the files and config directories a config was loaded from for changes with
+/// inotify
+///
+/// A file's directory is watched rather than the file itself, so editors that save by
+/// writing a new file and renaming it over the old one are noticed too. This covers the
+/// files pulled in by `include` as well.
+#[cfg(target_os = "linux")]
+pub struct ConfigWatcher {
+    inotify: tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>,
+    /// The file names to watch for in each watched directory, by watch descriptor; `None`
+    /// for any config file in a config directory
+    watches: HashMap<i32, Option<BTreeSet<OsString>>>,
+    buf: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl ConfigWatcher {
+    /// Starts watching `paths`; see `watch`.
+    pub fn new(paths: &[String]) -> io::Result<ConfigWatcher> {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) };
+        let mut watcher = ConfigWatcher {
+            inotify: tokio::io::unix::AsyncFd::new(inotify)?,
+            watches: HashMap::new(),
+            buf: vec![0u8; 4096],
+        };
+        watcher.watch(paths)?;
+        Ok(watcher)
+    }
+
+    /// Watches `paths`, the files and config directories a config was loaded from, in place
+    /// of what was watched before.
+    pub fn watch(&mut self, paths: &[String]) -> io::Result<()> {
+        use std::os::{fd::AsRawFd, unix::ffi::OsStrExt};
+
+        let fd = self.inotify.as_raw_fd();
+        for (watch, _) in self.watches.drain() {
+            unsafe { libc::inotify_rm_watch(fd, watch) };
+        }
+        for (dir, names) in watched_directories(paths)? {
+            let mut mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+            if names.is_none() {
+                // Removing a file from a config directory removes its entries
+                mask |= libc::IN_MOVED_FROM | libc::IN_DELETE;
+            }
+            let dir = std::ffi::CString::new(dir.as_os_str().as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let watch = unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) };
+            if watch < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.watches.insert(watch, names);
+        }
+        Ok(())
+    }
+
+    /// Waits until a watched file has been written or replaced, or a config file of a
+    /// watched directory has been written, replaced or removed.
+    pub async fn changed(&mut self) -> io::Result<()> {
+        use std::os::{fd::AsRawFd, unix::ffi::OsStrExt};
+
+        // struct inotify_event: wd, mask, cookie, len, then `len` bytes of NUL-padded name
+        const EVENT_LEN: usize = 16;
+
+        loop {
+            let mut guard = self.inotify.readable().await?;
+            let buf = &mut self.buf;
+            let received = guard.try_io(|inotify| {
+                let len = unsafe {
+                    libc::read(
+                        inotify.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                if len < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(len as usize)
+                }
+            });
+            let len = match received {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+
+            let mut offset = 0;
+            while offset + EVENT_LEN <= len {
+                let name_len = u32::from_ne_bytes([
+                    self.buf[offset + 12],
+                    self.buf[offset + 13],
+                    self.buf[offset + 14],
+                    self.buf[offset + 15],
+                ]) as usize;
+                let name = &self.buf[offset + EVENT_LEN..(offset + EVENT_LEN + name_len).min(len)];
+                let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                let watch = i32::from_ne_bytes([
+                    self.buf[offset],
+                    self.buf[offset + 1],
+                    self.buf[offset + 2],
+                    self.buf[offset + 3],
+                ]);
+                // Events of a removed watch, such as IN_IGNORED, match nothing
+                let matches = match self.watches.get(&watch) {
+                    Some(Some(names)) => names.contains(std::ffi::OsStr::from_bytes(name)),
+                    Some(None) => crate::config::is_config_file_name(&String::from_utf8_lossy(name)),
+                    None => false,
+                };
+                if matches {
+                    return Ok(());
+                }
+                offset += EVENT_LEN + name_len;
+            }
+        }
+    }
+}
+
+/// File change notifications are only available on Linux; elsewhere reload with SIGHUP
+#[cfg(not(target_os = "linux"))]
+pub struct ConfigWatcher;
+
+#[cfg(not(target_os = "linux"))]
+impl ConfigWatcher {
+    pub fn new(_paths: &[String]) -> io::Result<ConfigWatcher> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "config file notifications are only supported on Linux",
+        ))
+    }
+
+    pub fn watch(&mut self, _paths: &[String]) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub async fn changed(&mut self) -> io::Result<()> {
+        std::future::pending().await
+    }
+}
+
+/// The directories to watch for `paths`, each with the names of the files in it to watch
+/// for, or `None` for any config file when the directory is a config directory itself.
+/// Directories are compared by their canonical path, since inotify watches a directory once.
+fn watched_directories(paths: &[String]) -> io::Result<BTreeMap<PathBuf, Option<BTreeSet<OsString>>>> {
+    let mut directories: BTreeMap<PathBuf, Option<BTreeSet<OsString>>> = BTreeMap::new();
+    let canonical = |dir: &Path| fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    for path in paths.iter().map(Path::new).filter(|path| path.is_dir()) {
+        directories.insert(canonical(path), None);
+    }
+    for path in paths.iter().map(Path::new).filter(|path| !path.is_dir()) {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no file name", path.display())))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if let Some(names) = directories.entry(canonical(dir)).or_insert_with(|| Some(BTreeSet::new())) {
+            names.insert(name.to_os_string());
+        }
+    }
+    Ok(directories)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watches_the_directory_of_every_file() {
+        let dir = std::env::temp_dir().join(format!("rust-ddns-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::create_dir_all(dir.join("extra")).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let paths = [
+            path("ddns.yaml"),
+            path("conf.d/a.conf"),
+            path("extra/b.yaml"),
+            path("extra/../extra/c.yaml"),
+            path("conf.d"),
+        ];
+
+        let canonical = fs::canonicalize(&dir).unwrap();
+        let names = |names: &[&str]| Some(names.iter().map(OsString::from).collect::<BTreeSet<_>>());
+        let expected = BTreeMap::from([
+            (canonical.clone(), names(&["ddns.yaml"])),
+            // A config directory covers its own files
+            (canonical.join("conf.d"), None),
+            (canonical.join("extra"), names(&["b.yaml", "c.yaml"])),
+        ]);
+        assert_eq!(watched_directories(&paths).unwrap(), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notices_changes_to_included_files() {
+        let dir = std::env::temp_dir().join(format!("rust-ddns-watch-include-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("extra")).unwrap();
+        let main = dir.join("ddns.yaml").display().to_string();
+        let included = dir.join("extra/more.yaml").display().to_string();
+        fs::write(&main, "include: extra/more.yaml\n").unwrap();
+        fs::write(&included, "").unwrap();
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut watcher = ConfigWatcher::new(&[main.clone(), included.clone()]).unwrap();
+                // Another file in the same directory is not a change
+                fs::write(dir.join("extra/other.yaml"), "").unwrap();
+                fs::write(&included, "server: cloudflare\n").unwrap();
+                let changed = tokio::time::timeout(std::time::Duration::from_secs(5), watcher.changed()).await;
+                assert!(matches!(changed, Ok(Ok(()))));
+
+                // Once the include is gone, its file is no longer watched
+                watcher.watch(std::slice::from_ref(&main)).unwrap();
+                fs::write(&included, "").unwrap();
+                let changed = tokio::time::timeout(std::time::Duration::from_millis(200), watcher.changed()).await;
+                assert!(changed.is_err());
+            });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
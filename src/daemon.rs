@@ -0,0 +1,334 @@
+use std::{
+    cell::RefCell,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::time;
+
+use crate::{
+    api_client::{self, APIClient},
+    arg_parser::ConfigFormat,
+    config_watch::ConfigWatcher,
+    error,
+    logging::Logger,
+    netlink::{AddressChange, AddressMonitor},
+    status,
+    systemd::{self, Notifier, Progress},
+};
+
+/// How long address notifications must be quiet before a triggered check runs
+const DEBOUNCE: Duration = Duration::from_secs(2);
+/// How long the config file must be left alone before it is reloaded, so an editor's
+/// successive writes cause a single reload
+const CONFIG_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Command-line settings of the daemon
+#[derive(Debug)]
+pub struct Options {
+    /// Seconds between checks
+    pub interval: u64,
+    /// Upper bound of the random delay added to each interval, in seconds
+    pub jitter: u64,
+    /// Address of the HTTP status listener, if enabled
+    pub listen: Option<SocketAddr>,
+    /// Seconds a record may keep failing before `/healthz` reports it
+    pub health_threshold: u64,
+    /// node_exporter textfile to rewrite after every check
+    pub metrics_file: Option<PathBuf>,
+    /// Run the valid entries of a config that also has invalid ones
+    pub skip_invalid: bool,
+    /// Format of the config file, if not implied by its extension
+    pub config_format: Option<ConfigFormat>,
+}
+
+/// Keeps running the entries of `file` every `options.interval` seconds, plus a random
+/// delay of up to `options.jitter` seconds, until SIGTERM or SIGINT.
+///
+/// On Linux, a global address added to or removed from a relevant interface triggers a
+/// check straight away (see `api_client::watched_interfaces`), and the periodic check
+/// remains as a fallback.
+///
+/// The config is loaded once, so HTTP clients, discovered nameservers and the state file
+/// are shared by every cycle. It is reloaded on SIGHUP or, on Linux, when the file
+/// changes; an invalid new config is logged and the running one kept. A signal received
+/// during a cycle takes effect once the cycle's updates have finished.
+///
+/// With `options.listen`, the status of every record is served over HTTP (see
+/// `status::serve`), refreshed after each cycle.
+///
+/// Under systemd (`NOTIFY_SOCKET` set), readiness and a summary of each cycle are reported
+/// with sd_notify, and watchdog pings are sent if `WatchdogSec=` is configured, as long as
+/// no cycle or reload is stuck.
+pub async fn run(file: String, options: Options) -> Result<(), error::DynamicError> {
+    // The status listener runs in local tasks next to the update loop
+    tokio::task::LocalSet::new().run_until(run_local(file, options)).await
+}
+
+async fn run_local(file: String, options: Options) -> Result<(), error::DynamicError> {
+    let logger = Logger::new();
+    let mut signals = Signals::new()?;
+    let reports = Rc::new(RefCell::new(Vec::new()));
+    if let Some(address) = options.listen {
+        match tokio::net::TcpListener::bind(address).await {
+            Ok(listener) => {
+                logger.info(&format!("Serving status on http://{}/", address));
+                tokio::task::spawn_local(status::serve(listener, Rc::clone(&reports), options.health_threshold));
+            }
+            Err(err) => {
+                logger.error(&format!("Could not listen on {}: {}", address, err));
+                process::exit(1);
+            }
+        }
+    }
+    let (mut clients, sources) =
+        APIClient::from_config_file(file.clone(), options.config_format, options.skip_invalid).await;
+    logger.info(&format!(
+        "Daemon started: checking {} entries every {}s (jitter up to {}s)",
+        clients.len(),
+        options.interval,
+        options.jitter
+    ));
+    let notifier = match Notifier::from_env() {
+        Ok(notifier) => notifier.map(Rc::new),
+        Err(err) => {
+            logger.warning(&format!("Could not connect to NOTIFY_SOCKET: {}", err));
+            None
+        }
+    };
+    let progress = Rc::new(Progress::default());
+    if let Some(notifier) = &notifier {
+        notifier.notify(&format!("READY=1\nSTATUS=Checking {} entries", clients.len()));
+        if let Some(interval) = systemd::watchdog_interval() {
+            logger.debug(&format!("Sending watchdog pings every {}ms", interval.as_millis()));
+            tokio::task::spawn_local(systemd::watchdog(Rc::clone(notifier), interval, Rc::clone(&progress)));
+        }
+    }
+    let mut monitor = match AddressMonitor::new(api_client::watched_interfaces(&clients)) {
+        Ok(monitor) => Some(monitor),
+        Err(err) if cfg!(target_os = "linux") => {
+            logger.warning(&format!("Address change notifications unavailable ({}); polling only", err));
+            None
+        }
+        Err(err) => {
+            logger.debug(&format!("{}; polling only", err));
+            None
+        }
+    };
+    let mut watcher = match ConfigWatcher::new(&sources) {
+        Ok(watcher) => Some(watcher),
+        Err(err) if cfg!(target_os = "linux") => {
+            logger.warning(&format!("Not watching {} for changes ({}); reload with SIGHUP", file, err));
+            None
+        }
+        Err(err) => {
+            logger.debug(&format!("{}; reload with SIGHUP", err));
+            None
+        }
+    };
+
+    // The addresses were just determined while loading the config
+    let mut fresh = true;
+    loop {
+        progress.start();
+        if !fresh {
+            api_client::refresh_checkers(&clients).await;
+        }
+        fresh = false;
+        crate::execute_all(&clients).await;
+        *reports.borrow_mut() = clients.iter().flat_map(|client| client.report()).collect();
+        crate::write_metrics_file(options.metrics_file.as_deref());
+        if let Some(notifier) = &notifier {
+            notifier.notify(&format!("STATUS={}", systemd::describe(&reports.borrow())));
+        }
+        progress.finish();
+
+        let delay = Duration::from_secs(options.interval) + jitter_delay(options.jitter);
+        logger.debug(&format!("Next check in {}s", delay.as_secs()));
+        let deadline = time::Instant::now() + delay;
+        loop {
+            let reload = tokio::select! {
+                _ = time::sleep_until(deadline) => break,
+                change = address_change(&mut monitor) => match change {
+                    Ok(change) => {
+                        match change {
+                            Some(change) => logger.info(&format!("{}; checking now", change)),
+                            None => logger.info("Address notifications were dropped; checking now"),
+                        }
+                        settle(&mut monitor).await;
+                        break;
+                    }
+                    Err(err) => {
+                        logger.warning(&format!("Address change notifications failed ({}); polling only", err));
+                        monitor = None;
+                        false
+                    }
+                },
+                change = config_change(&mut watcher) => match change {
+                    Ok(()) => {
+                        settle_config(&mut watcher).await;
+                        logger.info(&format!("{} changed, reloading", file));
+                        true
+                    }
+                    Err(err) => {
+                        logger.warning(&format!("Watching {} failed ({}); reload with SIGHUP", file, err));
+                        watcher = None;
+                        false
+                    }
+                },
+                signal = signals.recv() => match signal {
+                    Signal::Reload => {
+                        logger.info("Received SIGHUP, reloading");
+                        true
+                    }
+                    Signal::Shutdown(name) => {
+                        logger.info(&format!("Received {}, shutting down", name));
+                        if let Some(notifier) = &notifier {
+                            notifier.notify("STOPPING=1");
+                        }
+                        return Ok(());
+                    }
+                },
+            };
+            if reload {
+                progress.start();
+                let loaded = APIClient::try_from_config_file(&file, options.config_format, options.skip_invalid).await;
+                progress.finish();
+                match loaded {
+                    Ok((config, sources)) => {
+                        for client in &config {
+                            client.inherit_status(&clients);
+                        }
+                        clients = config;
+                        if let Some(monitor) = monitor.as_mut() {
+                            monitor.watch(api_client::watched_interfaces(&clients));
+                        }
+                        if let Some(Err(err)) = watcher.as_mut().map(|watcher| watcher.watch(&sources)) {
+                            logger.warning(&format!("Watching {} failed ({}); reload with SIGHUP", file, err));
+                            watcher = None;
+                        }
+                        logger.info(&format!("Reloaded {}: {} entries", file, clients.len()));
+                        fresh = true;
+                        break;
+                    }
+                    Err(err) => {
+                        logger.error(&format!("{}; keeping the previous config", err));
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn address_change(monitor: &mut Option<AddressMonitor>) -> io::Result<Option<AddressChange>> {
+    match monitor {
+        Some(monitor) => monitor.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn config_change(watcher: &mut Option<ConfigWatcher>) -> io::Result<()> {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits until address notifications have been quiet for `DEBOUNCE`, so the burst from
+/// a reconnect triggers a single check.
+async fn settle(monitor: &mut Option<AddressMonitor>) {
+    loop {
+        tokio::select! {
+            _ = time::sleep(DEBOUNCE) => return,
+            change = address_change(monitor) => {
+                if change.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Waits until the config file has been left alone for `CONFIG_DEBOUNCE`.
+async fn settle_config(watcher: &mut Option<ConfigWatcher>) {
+    loop {
+        tokio::select! {
+            _ = time::sleep(CONFIG_DEBOUNCE) => return,
+            change = config_change(watcher) => {
+                if change.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// A random delay between zero and `jitter` seconds, so many hosts started together do
+/// not hit the providers at the same moment.
+fn jitter_delay(jitter: u64) -> Duration {
+    if jitter == 0 {
+        return Duration::ZERO;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        ^ ((std::process::id() as u128) << 32);
+    let millis = nanos % (jitter as u128 * 1000 + 1);
+    Duration::from_millis(millis as u64)
+}
+
+/// What a received signal asks the daemon to do
+enum Signal {
+    Reload,
+    Shutdown(&'static str),
+}
+
+/// Waits for the signals the daemon handles
+#[cfg(unix)]
+struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> std::io::Result<Signals> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.terminate.recv() => Signal::Shutdown("SIGTERM"),
+            _ = self.interrupt.recv() => Signal::Shutdown("SIGINT"),
+            _ = self.hangup.recv() => Signal::Reload,
+        }
+    }
+}
+
+/// Waits for the signals the daemon handles
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> std::io::Result<Signals> {
+        Ok(Signals)
+    }
+
+    async fn recv(&mut self) -> Signal {
+        let _ = tokio::signal::ctrl_c().await;
+        Signal::Shutdown("Ctrl-C")
+    }
+}
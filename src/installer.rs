@@ -1,22 +1,16 @@
 use std::process;
 
+/// `WatchdogSec=` of the daemon service; the daemon pings at half this interval
+#[cfg(target_os = "linux")]
+const WATCHDOG_SEC: u64 = 60;
+
 #[allow(dead_code)]
 pub fn parse_interval_secs(s: &str) -> u64 {
-    if let Some(mins) = s.strip_suffix("min") {
-        if let Ok(n) = mins.parse::<u64>() {
-            return n * 60;
-        }
-    }
-    if let Some(hours) = s.strip_suffix("h") {
-        if let Ok(n) = hours.parse::<u64>() {
-            return n * 3600;
-        }
-    }
-    s.parse::<u64>().unwrap_or(300)
+    crate::time_tools::parse_duration_secs(s).unwrap_or(300)
 }
 
 #[cfg(target_os = "linux")]
-pub fn install(interval: &str, log_file: Option<&str>, config_file: Option<&str>) {
+pub fn install(interval: &str, log_file: Option<&str>, config_file: Option<&str>, daemon: bool) {
     use std::fs;
     use std::os::unix::fs::PermissionsExt;
 
@@ -52,6 +46,11 @@ pub fn install(interval: &str, log_file: Option<&str>, config_file: Option<&str>
         String::new()
     };
 
+    if daemon {
+        install_daemon_service(interval, log_file, &config_arg, &home, &bin_dir, &binary_dest);
+        return;
+    }
+
     let wrapper_path = format!("{}/ddnsd-rust-ddns", bin_dir);
     let wrapper_content = format!("#!/bin/bash\n\
 RUST_DDNS_LOG_FILE={log}\n\
@@ -98,6 +97,11 @@ Environment=PATH={bin_dir}:/usr/local/bin:/usr/bin:/bin\n",
         bin_dir = bin_dir,
     );
 
+    // A daemon service from a previous install would keep running alongside the timer
+    let _ = process::Command::new("sudo")
+        .args(["systemctl", "disable", "--now", "rust-ddns.service"])
+        .stderr(process::Stdio::null())
+        .status();
     write_file_as_root("/etc/systemd/system/rust-ddns.timer", &timer_content);
     write_file_as_root("/etc/systemd/system/rust-ddns.service", &service_content);
 
@@ -107,6 +111,72 @@ Environment=PATH={bin_dir}:/usr/local/bin:/usr/bin:/bin\n",
     println!("Run: sudo systemctl enable --now rust-ddns.timer");
 }
 
+/// Installs `rust-ddns daemon` as a `Type=notify` service with a watchdog, replacing the
+/// timer of a previous install.
+#[cfg(target_os = "linux")]
+fn install_daemon_service(
+    interval: &str,
+    log_file: Option<&str>,
+    config_arg: &str,
+    home: &str,
+    bin_dir: &str,
+    binary: &str,
+) {
+    if !matches!(crate::time_tools::parse_duration_secs(interval), Some(secs) if secs > 0) {
+        eprintln!("ERROR: --interval must be a duration such as 5min, 30s or 1h (got '{}')", interval);
+        process::exit(1);
+    }
+
+    // Without a log file the output goes to the journal
+    let output = match log_file {
+        Some(log) => format!("StandardOutput=append:{log}\nStandardError=append:{log}\n", log = log),
+        None => String::new(),
+    };
+
+    let service_content = format!("[Unit]\n\
+Description=rust-ddns dynamic DNS daemon\n\
+Wants=network-online.target\n\
+After=network-online.target\n\
+\n\
+[Service]\n\
+Type=notify\n\
+User={user}\n\
+WorkingDirectory={home}\n\
+ExecStart={binary}{config} daemon --interval {interval}\n\
+ExecReload=/bin/kill -HUP $MAINPID\n\
+WatchdogSec={watchdog}\n\
+Restart=on-failure\n\
+Environment=HOME={home}\n\
+Environment=PATH={bin_dir}:/usr/local/bin:/usr/bin:/bin\n\
+{output}\
+\n\
+[Install]\n\
+WantedBy=multi-user.target\n",
+        user = std::env::var("USER").unwrap_or_else(|_| "nobody".to_string()),
+        home = home,
+        binary = binary,
+        config = config_arg,
+        interval = interval,
+        watchdog = WATCHDOG_SEC,
+        bin_dir = bin_dir,
+        output = output,
+    );
+
+    let _ = process::Command::new("sudo")
+        .args(["systemctl", "disable", "--now", "rust-ddns.timer"])
+        .stderr(process::Stdio::null())
+        .status();
+    let _ = process::Command::new("sudo")
+        .args(["rm", "-f", "/etc/systemd/system/rust-ddns.timer"])
+        .status();
+    write_file_as_root("/etc/systemd/system/rust-ddns.service", &service_content);
+
+    run_sudo(&["systemctl", "daemon-reload"]);
+
+    println!("Installation complete!");
+    println!("Run: sudo systemctl enable --now rust-ddns.service");
+}
+
 #[cfg(target_os = "linux")]
 pub fn uninstall(purge: bool) {
     use std::fs;
@@ -146,14 +216,19 @@ pub fn uninstall(purge: bool) {
         let log = format!("{}/.rust-ddns.log", home);
         let _ = fs::remove_file(&conf);
         let _ = fs::remove_file(&log);
-        println!("Purged config and log files.");
+        let _ = fs::remove_dir_all(crate::state::state_dir());
+        println!("Purged config, log and state files.");
     }
 
     println!("Uninstallation complete!");
 }
 
 #[cfg(target_os = "macos")]
-pub fn install(interval: &str, log_file: Option<&str>, config_file: Option<&str>) {
+pub fn install(interval: &str, log_file: Option<&str>, config_file: Option<&str>, daemon: bool) {
+    if daemon {
+        eprintln!("ERROR: --daemon is only supported with systemd on Linux; run `rust-ddns daemon` under your own service manager instead.");
+        process::exit(1);
+    }
     use std::fs;
     use std::os::unix::fs::PermissionsExt;
 
@@ -289,14 +364,19 @@ pub fn uninstall(purge: bool) {
     if purge {
         let _ = fs::remove_file(format!("{}/.ddns.conf", home));
         let _ = fs::remove_file(format!("{}/.rust-ddns.log", home));
-        println!("Purged config and log files.");
+        let _ = fs::remove_dir_all(crate::state::state_dir());
+        println!("Purged config, log and state files.");
     }
 
     println!("Uninstallation complete!");
 }
 
 #[cfg(target_os = "windows")]
-pub fn install(interval: &str, log_file: Option<&str>, config_file: Option<&str>) {
+pub fn install(interval: &str, log_file: Option<&str>, config_file: Option<&str>, daemon: bool) {
+    if daemon {
+        eprintln!("ERROR: --daemon is only supported with systemd on Linux; run `rust-ddns daemon` under your own service manager instead.");
+        process::exit(1);
+    }
     use std::fs;
 
     let local_app_data = std::env::var("LOCALAPPDATA").unwrap_or_else(|_| {
@@ -396,7 +476,7 @@ pub fn uninstall(purge: bool) {
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-pub fn install(_interval: &str, _log_file: Option<&str>, _config_file: Option<&str>) {
+pub fn install(_interval: &str, _log_file: Option<&str>, _config_file: Option<&str>, _daemon: bool) {
     eprintln!("ERROR: install subcommand is not supported on this platform.");
     process::exit(1);
 }
@@ -1,107 +1,203 @@
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{
+    cell::{Cell, OnceCell},
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
 
-use crate::{error, logging};
+use crate::{
+    error, logging,
+    resolver::{LookupError, RecordType, Resolver},
+};
 
-const V4_URL: &str = "https://api4.ipify.org";
+pub mod dns;
+pub mod http;
+pub mod interface;
+pub mod router;
+pub mod stun;
+
+/// Returns `true` for the RFC 6598 shared address space used by carrier-grade NAT
+/// (100.64.0.0/10), which is never a public address.
+pub fn is_shared_address(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && octets[1] & 0xc0 == 64
+}
+
+/// The address family of an IP address, used to pick between A and AAAA records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    /// Returns `true` if `ip` belongs to this address family.
+    pub fn matches(&self, ip: &IpAddr) -> bool {
+        matches!(
+            (self, ip),
+            (Family::V4, IpAddr::V4(_)) | (Family::V6, IpAddr::V6(_))
+        )
+    }
+
+    fn record_type(&self) -> RecordType {
+        match self {
+            Family::V4 => RecordType::A,
+            Family::V6 => RecordType::Aaaa,
+        }
+    }
+}
+
+impl Display for Family {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Family::V4 => write!(f, "IPv4"),
+            Family::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Where the host's actual (public) IP address is discovered from
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpSource {
+    /// One or more HTTP echo services (by default ipify)
+    Http(http::HttpSource),
+    /// The addresses assigned to a local network interface
+    Interface(interface::InterfaceSource),
+    /// The external address reported by the internet gateway
+    Router(router::RouterSource),
+    /// A DNS "whoami" query such as `myip.opendns.com`
+    Dns(dns::DnsSource),
+    /// The reflexive address reported by STUN servers
+    Stun(stun::StunSource),
+}
+
+impl Default for IpSource {
+    fn default() -> Self {
+        IpSource::Http(http::HttpSource::default())
+    }
+}
+
+impl Display for IpSource {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            IpSource::Http(_) => write!(f, "http"),
+            IpSource::Interface(source) => write!(f, "interface {}", source.name),
+            IpSource::Router(_) => write!(f, "router"),
+            IpSource::Dns(_) => write!(f, "dns"),
+            IpSource::Stun(_) => write!(f, "stun"),
+        }
+    }
+}
 
 /// Struct that encapsulates all the necessary state and implementations needed for
 /// comparing the actual IP of the server it's running on against the current DNS records
 #[derive(Debug)]
 pub struct IP {
-    actual: Option<Ipv4Addr>,
+    source: IpSource,
+    actual_v4: Cell<Option<Ipv4Addr>>,
+    actual_v6: Cell<Option<Ipv6Addr>>,
+    /// The clients of an `Http` source, built on first use and kept for later checks
+    http_v4: OnceCell<reqwest::Client>,
+    http_v6: OnceCell<reqwest::Client>,
 }
 
 impl IP {
-    /// Retrieves the current IP address of the host machine.
+    /// Retrieves the current IP address of the host machine for the given address family.
     ///
-    /// This function makes an HTTP GET request to the endpoint defined by `V4_URL`
-    /// constant (by default <https://api4.ipify.org>) and retrieves the current IP address.
-    ///
-    /// The IP address is determined by the outbound connection from the system (client)
-    /// to the ipify service, as it perceives it.
+    /// The address is discovered through the configured `IpSource`. For the default
+    /// HTTP source this is the address the ipify service sees the outbound connection
+    /// coming from (<https://api4.ipify.org> or <https://api6.ipify.org>).
     ///
     /// # Returns
     ///
     /// A `Result` which is:
     ///
-    /// * `Ok` - A string that represent the current IP address of the machine. When the
-    ///   function succeeds, it returns this variant containing the IP address.
-    /// * `Err` - Contains a `reqwest::Error` when there's a failure in getting
-    ///   the IP address. For example, if there is no network connection or the ipify
-    ///   service is down.
+    /// * `Ok` - The current IP address of the machine, guaranteed to be of `family`.
+    /// * `Err` - Contains a `DynamicError` when there's a failure in getting
+    ///   the IP address. For example, if there is no network connection or every
+    ///   configured service is down.
     ///
     /// # Example
     ///
     /// ```rust
     ///
     /// async {
-    ///     match IP::get_actual_ip().await {
+    ///     let ip = IP::new(IpSource::default());
+    ///     match ip.get_actual_ip(Family::V4).await {
     ///         Ok(ip) => println!("The actual IP is: {}", ip),
     ///         Err(e) => eprintln!("Failed to get the actual IP: {:?}", e),
     ///     }
     /// };
     /// ```
-    pub async fn get_actual_ip() -> Result<String, reqwest::Error> {
-        reqwest::get(V4_URL).await?.text().await
+    pub async fn get_actual_ip(&self, family: Family) -> Result<IpAddr, error::DynamicError> {
+        match &self.source {
+            IpSource::Http(source) => source.fetch(self.http_client(family)?, family).await,
+            IpSource::Interface(source) => source.fetch(family),
+            IpSource::Router(source) => source.fetch(family).await,
+            IpSource::Dns(source) => source.fetch(family).await,
+            IpSource::Stun(source) => source.fetch(family).await,
+        }
     }
 
-    /// Checks the current IP for a domain according to DNS records (depends on dig being installed on the current system)
+    /// Checks the current IPs for a domain according to its DNS records, following CNAMEs
     ///
     /// # Arguments
     ///
     /// * `domain` - A string slice that holds the domain for which we want the IP
+    /// * `family` - Whether to look up the A (IPv4) or AAAA (IPv6) record
+    /// * `resolver` - The resolver to send the queries to
     ///
     /// # Errors
     ///
-    /// This function will return an error if the dig command fails
+    /// This function will return `LookupError::NxDomain` if the domain does not exist,
+    /// or `LookupError::Network` if no nameserver could answer
     ///
     /// # Example
     ///
     /// ```
-    /// let ip = IP::get_previous_ip("https://example.com").await;
-    /// println!("{:?}", ip);
+    /// let ips = IP::get_previous_ip("example.com", Family::V4, &Resolver::system()).await;
+    /// println!("{:?}", ips);
     /// ```
-    async fn get_previous_ip(domain: &str) -> Result<String, command_line::Errors> {
+    async fn get_previous_ip(domain: &str, family: Family, resolver: &Resolver) -> Result<Vec<IpAddr>, LookupError> {
         let logger = logging::Logger::new();
-        match command_line::sh(&format!("dig +short {}", domain)) {
-            Ok(output) => {
-                let mut result = output;
-                trim_newline(&mut result);
-                logger.debug(&format!(
-                    "dig returned IP address: '{}' for domain: '{}'",
-                    result, domain
-                ));
-                Ok(result)
-            }
-            Err(err) => {
-                logger.error(&format!("dig command failed with output: {}", err));
-                Err(err)
-            }
+        let result = resolver.lookup(domain, family.record_type()).await;
+        match &result {
+            Ok(ips) => logger.debug(&format!(
+                "DNS returned {} addresses {:?} for domain: '{}'",
+                family.record_type(),
+                ips,
+                domain
+            )),
+            Err(err) => logger.debug(&format!("DNS lookup for '{}' failed: {}", domain, err)),
         }
+        result
     }
 
-    /// Compares the current domain's IP with the host's actual IP.
+    /// Compares the current domain's IP with the host's actual IP of the same address family.
     ///
     /// # Arguments
     ///
     /// * `domain` - A string slice that holds the domain for which we want to compare the host's IP.
+    /// * `family` - The address family (A or AAAA record) to compare.
+    /// * `resolver` - The resolver used to look up the domain's current records.
     ///
     /// # Returns
     ///
     /// A `Result` which is:
     ///
-    /// * `Ok` - `true` if the domain IP and the host's actual IP are different, `false` if they are the same.
+    /// * `Ok` - `true` if the domain's records do not contain the host's actual IP (including when
+    ///   the domain does not exist), `false` if they do.
     /// * `Err` - Contains a `DynamicError` if it fails to compare the IPs.
     ///
     /// # Errors
     ///
-    /// This function will return a `Box<dyn std::error::Error>` (or a `DynamicError`) if checking the DNS fails
-    /// or if IP address from the string conversion fails.
+    /// This function will return a `Box<dyn std::error::Error>` (or a `DynamicError`) if no nameserver
+    /// could be reached, so a network failure never triggers an update. It also fails if the host's
+    /// actual IP for `family` could not be determined.
     ///
     /// # Example
     ///
     /// ```rust
-    /// let comparison = IP::compare("https://example.com").await;
+    /// let comparison = ip.compare("example.com", Family::V4, &Resolver::system()).await;
     /// match comparison {
     ///     Ok(result) => {
     ///         if result {
@@ -113,78 +209,147 @@ impl IP {
     ///     Err(e) => println!("Failed to compare the IP addresses: {:?}", e),
     /// }
     /// ```
-    pub async fn compare(&self, domain: &str) -> Result<bool, crate::error::DynamicError> {
+    pub async fn compare(
+        &self,
+        domain: &str,
+        family: Family,
+        resolver: &Resolver,
+    ) -> Result<bool, crate::error::DynamicError> {
         let logger = logging::Logger::new();
-        let current = match IP::get_previous_ip(domain).await {
-            Ok(output) => output,
-            Err(err) => return Err(Box::new(err)),
+        let actual = match self.actual_ip(family) {
+            Some(ip) => ip,
+            None => return Err(format!("Could not determine actual {} address", family).into()),
         };
-        let current_ip = if current.is_empty() {
-            None
-        } else {
-            Some(Ipv4Addr::from_str(&current)?)
+        let current = match IP::get_previous_ip(domain, family, resolver).await {
+            Ok(ips) => ips,
+            Err(LookupError::NxDomain(_)) => {
+                logger.info(&format!("{} does not exist in DNS yet", domain));
+                Vec::new()
+            }
+            Err(err) => return Err(Box::new(err)),
         };
+        if current.len() > 1 {
+            logger.warning(&format!(
+                "{} has {} {} records: {:?}",
+                domain,
+                current.len(),
+                family.record_type(),
+                current
+            ));
+        }
 
-        if self.actual != current_ip {
+        if !current.contains(&actual) {
             logger.info(&format!(
-                "IP address changed: New IP: {}",
-                self.actual.unwrap()
+                "{} {} address changed: New IP: {}",
+                domain, family, actual
             ));
             return Ok(true);
         } else {
-            logger.debug("IP address did not change");
+            logger.debug(&format!("{} {} address did not change", domain, family));
         }
         Ok(false)
     }
 
-    pub fn actual_ip(&self) -> Option<std::net::Ipv4Addr> {
-        self.actual
+    /// Returns the source this checker discovers the actual IP from.
+    pub fn source(&self) -> &IpSource {
+        &self.source
     }
 
-    /// Constructs a new instance of the `IP` structure with `actual`
-    pub fn new() -> IP {
-        IP { actual: None }
+    /// Returns the host's actual IP for the given address family, if it has been determined.
+    pub fn actual_ip(&self, family: Family) -> Option<IpAddr> {
+        match family {
+            Family::V4 => self.actual_v4.get().map(IpAddr::V4),
+            Family::V6 => self.actual_v6.get().map(IpAddr::V6),
+        }
+    }
+
+    /// Constructs a new instance of the `IP` structure for `source` with no actual addresses set
+    pub fn new(source: IpSource) -> IP {
+        IP {
+            source,
+            actual_v4: Cell::new(None),
+            actual_v6: Cell::new(None),
+            http_v4: OnceCell::new(),
+            http_v6: OnceCell::new(),
+        }
     }
 
-    /// Sets the `actual` field of the `IP` structure if it is currently `None`.
+    /// Returns the HTTP client for `family`, building it the first time.
+    fn http_client(&self, family: Family) -> Result<&reqwest::Client, error::DynamicError> {
+        let cell = match family {
+            Family::V4 => &self.http_v4,
+            Family::V6 => &self.http_v6,
+        };
+        if let Some(client) = cell.get() {
+            return Ok(client);
+        }
+        let client = http::client(family)?;
+        Ok(cell.get_or_init(|| client))
+    }
+
+    /// Sets the actual IPv4 and/or IPv6 address of the `IP` structure if currently `None`.
+    ///
+    /// It gets each actual IP using the `get_actual_ip` method. Only the requested families
+    /// are looked up, so a host without IPv6 connectivity does not need to reach the IPv6
+    /// endpoint unless an AAAA record is configured. A family that cannot be determined is
+    /// logged and left unset; `compare` will then fail for that family only.
     ///
-    /// It gets the actual IP using the `get_actual_ip` method.
-    /// Once the `actual` field is set, it logs the fetched IP address.
+    /// # Arguments
+    ///
+    /// * `families` - The address families that need to be determined.
     ///
     /// # Returns
     ///
-    /// Returns `Ok(())` if `actual` field was set successfully, or an `Err` wrapping
-    /// a `DynamicError` if getting actual IP or conversion from a string to `Ipv4Addr` failed.
+    /// Returns `Ok(())` if at least one requested family was set successfully, or an `Err`
+    /// wrapping a `DynamicError` if none of them could be determined.
     ///
     /// # Example
     ///
     /// ```rust
     /// // Create a new IP instance and set actual.
-    /// let mut ip = IP::new();
-    /// match ip.set_actual().await {
+    /// let ip = IP::new(IpSource::default());
+    /// match ip.set_actual(&[Family::V4, Family::V6]).await {
     ///     Ok(()) => println!("The actual IP has been set."),
     ///     Err(e) => println!("Failed to set the actual IP: {:?}", e),
     /// }
     /// ```
-    pub async fn set_actual(&mut self) -> Result<(), error::DynamicError> {
-        if self.actual.is_none() {
-            self.actual =
-                Some(Ipv4Addr::from_str(IP::get_actual_ip().await?.as_str())?);
-            let logger = crate::logging::Logger::new();
-            logger.debug(&format!(
-                "ipify returned IP address: '{}'",
-                self.actual.unwrap()
-            ));
+    pub async fn set_actual(&self, families: &[Family]) -> Result<(), error::DynamicError> {
+        let logger = crate::logging::Logger::new();
+        let mut last_err: Option<error::DynamicError> = None;
+        for family in families {
+            if self.actual_ip(*family).is_some() {
+                continue;
+            }
+            let started = std::time::Instant::now();
+            let result = self.get_actual_ip(*family).await;
+            crate::metrics::ip_check(&self.source.to_string(), &family.to_string(), result.is_ok(), started.elapsed());
+            match result {
+                Ok(ip) => {
+                    logger.debug(&format!("{} source returned {} address: '{}'", self.source, family, ip));
+                    match ip {
+                        IpAddr::V4(v4) => self.actual_v4.set(Some(v4)),
+                        IpAddr::V6(v6) => self.actual_v6.set(Some(v6)),
+                    }
+                }
+                Err(err) => {
+                    logger.error(&format!("Could not determine actual {} address: {}", family, err));
+                    last_err = Some(err);
+                }
+            }
+        }
+        match last_err {
+            Some(err) if families.iter().all(|f| self.actual_ip(*f).is_none()) => Err(err),
+            _ => Ok(()),
         }
-        Ok(())
     }
-}
 
-fn trim_newline(s: &mut String) {
-    if s.ends_with('\n') {
-        s.pop();
-        if s.ends_with('\r') {
-            s.pop();
-        }
+    /// Forgets the actual addresses and determines them again, as `set_actual` does.
+    ///
+    /// A family that can no longer be determined is left unset, so its records fail to
+    /// compare instead of being updated with a stale address.
+    pub async fn refresh(&self, families: &[Family]) -> Result<(), error::DynamicError> {
+        self.actual_v4.set(None);
+        self.actual_v6.set(None);
+        self.set_actual(families).await
     }
 }
@@ -0,0 +1,143 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use super::Family;
+use crate::{
+    error, logging,
+    resolver::{self, Class, RecordType},
+};
+
+/// A DNS service that answers with the address the query came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsProvider {
+    /// `myip.opendns.com` A/AAAA at resolver1.opendns.com
+    OpenDns,
+    /// `whoami.cloudflare` TXT in the CHAOS class at 1.1.1.1
+    Cloudflare,
+    /// `o-o.myaddr.l.google.com` TXT at ns1.google.com
+    Google,
+}
+
+impl FromStr for DnsProvider {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<DnsProvider, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "opendns" => Ok(DnsProvider::OpenDns),
+            "cloudflare" => Ok(DnsProvider::Cloudflare),
+            "google" => Ok(DnsProvider::Google),
+            _ => Err(()),
+        }
+    }
+}
+
+impl DnsProvider {
+    fn name(&self) -> &'static str {
+        match self {
+            DnsProvider::OpenDns => "myip.opendns.com",
+            DnsProvider::Cloudflare => "whoami.cloudflare",
+            DnsProvider::Google => "o-o.myaddr.l.google.com",
+        }
+    }
+
+    fn default_resolvers(&self) -> Vec<SocketAddr> {
+        let addresses: &[&str] = match self {
+            DnsProvider::OpenDns => &[
+                "208.67.222.222:53",
+                "208.67.220.220:53",
+                "[2620:119:35::35]:53",
+                "[2620:119:53::53]:53",
+            ],
+            DnsProvider::Cloudflare => &[
+                "1.1.1.1:53",
+                "1.0.0.1:53",
+                "[2606:4700:4700::1111]:53",
+                "[2606:4700:4700::1001]:53",
+            ],
+            DnsProvider::Google => &["216.239.32.10:53", "[2001:4860:4802:32::a]:53"],
+        };
+        addresses
+            .iter()
+            .filter_map(|a| SocketAddr::from_str(a).ok())
+            .collect()
+    }
+}
+
+/// Public IP discovery through a DNS "whoami" query
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsSource {
+    pub provider: DnsProvider,
+    /// Overrides the provider's resolver addresses (e.g. a local stub server)
+    pub resolvers: Option<Vec<SocketAddr>>,
+}
+
+impl DnsSource {
+    /// Asks the provider's resolvers for the address of the query's origin.
+    ///
+    /// Queries for `family` are only sent to resolvers of that family, since the answer
+    /// is the source address of the query itself.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no resolver of `family` is configured or none of them answers with an
+    /// address of that family.
+    pub async fn fetch(&self, family: Family) -> Result<IpAddr, error::DynamicError> {
+        let logger = logging::Logger::new();
+        let resolvers: Vec<SocketAddr> = self
+            .resolvers
+            .clone()
+            .unwrap_or_else(|| self.provider.default_resolvers())
+            .into_iter()
+            .filter(|r| family.matches(&r.ip()))
+            .collect();
+        if resolvers.is_empty() {
+            return Err(format!("no {} resolvers configured for the DNS IP source", family).into());
+        }
+
+        let (record_type, class) = match (self.provider, family) {
+            (DnsProvider::OpenDns, Family::V4) => (RecordType::A, Class::Internet),
+            (DnsProvider::OpenDns, Family::V6) => (RecordType::Aaaa, Class::Internet),
+            (DnsProvider::Cloudflare, _) => (RecordType::Txt, Class::Chaos),
+            (DnsProvider::Google, _) => (RecordType::Txt, Class::Internet),
+        };
+
+        let mut last_err: error::DynamicError = "no DNS resolvers responded".into();
+        for resolver in resolvers {
+            let response = match resolver::query(resolver, self.provider.name(), record_type, class, true).await {
+                Ok(response) => response,
+                Err(err) => {
+                    logger.warning(&format!("DNS IP lookup via {} failed: {}", resolver, err));
+                    last_err = err;
+                    continue;
+                }
+            };
+            let candidates: Vec<IpAddr> = match record_type {
+                RecordType::Txt => response
+                    .txt()
+                    .iter()
+                    .filter_map(|t| IpAddr::from_str(t.trim()).ok())
+                    .collect(),
+                _ => response.addresses(),
+            };
+            match candidates.into_iter().find(|ip| family.matches(ip)) {
+                Some(ip) => {
+                    logger.debug(&format!("{} via {} returned '{}'", self.provider.name(), resolver, ip));
+                    return Ok(ip);
+                }
+                None => {
+                    last_err = format!(
+                        "{} via {} returned no {} address (rcode {})",
+                        self.provider.name(),
+                        resolver,
+                        family,
+                        response.rcode
+                    )
+                    .into();
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::Duration,
+};
+
+use futures::future;
+use regex::Regex;
+
+use super::Family;
+use crate::{error, logging};
+
+const V4_URL: &str = "https://api4.ipify.org";
+const V6_URL: &str = "https://api6.ipify.org";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the IP address is extracted from an echo service's response body
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseFormat {
+    /// The whole body is the address
+    Text,
+    /// The body is JSON; the address is found at a dotted field path, e.g. `data.ip`
+    Json(String),
+    /// The address is the first capture group (or the whole match) of a regex
+    Regex(String),
+}
+
+impl ResponseFormat {
+    /// Extracts the raw address string from a response body.
+    fn extract(&self, body: &str) -> Result<String, error::DynamicError> {
+        match self {
+            ResponseFormat::Text => Ok(body.trim().to_string()),
+            ResponseFormat::Json(path) => {
+                let root: serde_json::Value = serde_json::from_str(body)?;
+                let mut value = &root;
+                for key in path.split('.') {
+                    value = match key.parse::<usize>() {
+                        Ok(index) if value.is_array() => &value[index],
+                        _ => &value[key],
+                    };
+                }
+                match value.as_str() {
+                    Some(s) => Ok(s.trim().to_string()),
+                    None => Err(format!("field '{}' not found in JSON response", path).into()),
+                }
+            }
+            ResponseFormat::Regex(pattern) => {
+                let re = Regex::new(pattern)?;
+                let captures = re
+                    .captures(body)
+                    .ok_or_else(|| format!("pattern '{}' did not match response", pattern))?;
+                let matched = captures.get(1).or_else(|| captures.get(0)).unwrap();
+                Ok(matched.as_str().trim().to_string())
+            }
+        }
+    }
+}
+
+/// A single "what is my IP" web service
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub url: String,
+    pub format: ResponseFormat,
+    /// Restricts the endpoint to one address family (e.g. `api4.ipify.org` only answers over IPv4)
+    pub family: Option<Family>,
+}
+
+impl Endpoint {
+    async fn query(&self, client: &reqwest::Client, family: Family) -> Result<IpAddr, error::DynamicError> {
+        let body = client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let ip = IpAddr::from_str(&self.format.extract(&body)?)?;
+        if family.matches(&ip) {
+            Ok(ip)
+        } else {
+            Err(format!("{} returned '{}', expected an {} address", self.url, ip, family).into())
+        }
+    }
+}
+
+/// How the results of several endpoints are combined
+#[derive(Debug, Clone, PartialEq)]
+pub enum Strategy {
+    /// Try each endpoint in order and use the first address returned
+    Fallback,
+    /// Query every endpoint in parallel and require at least this many to agree; by default
+    /// a majority of the endpoints of the requested family
+    Consensus(Option<usize>),
+}
+
+/// Public IP discovery through one or more HTTP echo services
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpSource {
+    pub endpoints: Vec<Endpoint>,
+    pub strategy: Strategy,
+}
+
+impl Default for HttpSource {
+    /// The ipify services, one per address family
+    fn default() -> Self {
+        Self {
+            endpoints: vec![
+                Endpoint {
+                    url: V4_URL.to_string(),
+                    format: ResponseFormat::Text,
+                    family: Some(Family::V4),
+                },
+                Endpoint {
+                    url: V6_URL.to_string(),
+                    format: ResponseFormat::Text,
+                    family: Some(Family::V6),
+                },
+            ],
+            strategy: Strategy::Fallback,
+        }
+    }
+}
+
+impl HttpSource {
+    /// How many endpoints of `family` must agree under `Strategy::Consensus`: the configured
+    /// quorum, or else a majority of them.
+    fn quorum(&self, family: Family) -> usize {
+        match self.strategy {
+            Strategy::Consensus(Some(quorum)) => quorum,
+            _ => self.endpoints_for(family).len() / 2 + 1,
+        }
+    }
+
+    /// The endpoints queried for `family`: those of that family and those of either.
+    pub fn endpoints_for(&self, family: Family) -> Vec<&Endpoint> {
+        self.endpoints
+            .iter()
+            .filter(|e| e.family.is_none() || e.family == Some(family))
+            .collect()
+    }
+
+    /// Retrieves the current IP address of the host machine for the given address family.
+    ///
+    /// `client` must be bound to `family` (see `client`), so services that answer over both
+    /// IPv4 and IPv6 report the address of the requested family.
+    ///
+    /// # Errors
+    ///
+    /// With `Strategy::Fallback` this fails if every endpoint fails; with
+    /// `Strategy::Consensus(n)` it fails unless at least `n` endpoints return the same address.
+    pub async fn fetch(&self, client: &reqwest::Client, family: Family) -> Result<IpAddr, error::DynamicError> {
+        let logger = logging::Logger::new();
+        let endpoints = self.endpoints_for(family);
+        if endpoints.is_empty() {
+            return Err(format!("no IP endpoints configured for {}", family).into());
+        }
+
+        match self.strategy {
+            Strategy::Fallback => {
+                let mut last_err: error::DynamicError = "no IP endpoints responded".into();
+                for endpoint in endpoints {
+                    match endpoint.query(client, family).await {
+                        Ok(ip) => {
+                            logger.debug(&format!("{} returned {} address: '{}'", endpoint.url, family, ip));
+                            return Ok(ip);
+                        }
+                        Err(err) => {
+                            logger.warning(&format!("IP endpoint {} failed: {}", endpoint.url, err));
+                            last_err = err;
+                        }
+                    }
+                }
+                Err(last_err)
+            }
+            Strategy::Consensus(_) => {
+                let quorum = self.quorum(family);
+                let results = future::join_all(endpoints.iter().map(|e| e.query(client, family))).await;
+                let mut addresses = Vec::new();
+                for (endpoint, result) in endpoints.iter().zip(results) {
+                    match result {
+                        Ok(ip) => {
+                            logger.debug(&format!("{} returned {} address: '{}'", endpoint.url, family, ip));
+                            addresses.push(ip);
+                        }
+                        Err(err) => {
+                            logger.warning(&format!("IP endpoint {} failed: {}", endpoint.url, err));
+                        }
+                    }
+                }
+                consensus(&addresses, quorum).map_err(|votes| {
+                    format!(
+                        "no {} address was reported by at least {} of {} endpoints (got {:?})",
+                        family,
+                        quorum,
+                        endpoints.len(),
+                        votes
+                    )
+                    .into()
+                })
+            }
+        }
+    }
+}
+
+/// Builds the client for the queries of `family`, bound to its unspecified address.
+pub fn client(family: Family) -> Result<reqwest::Client, error::DynamicError> {
+    let local_address = match family {
+        Family::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        Family::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    Ok(reqwest::Client::builder()
+        .local_address(local_address)
+        .timeout(TIMEOUT)
+        .build()?)
+}
+
+/// The address reported at least `quorum` times among `addresses`, or else the number of
+/// times each address was reported.
+fn consensus(addresses: &[IpAddr], quorum: usize) -> Result<IpAddr, Vec<(IpAddr, usize)>> {
+    let mut votes: Vec<(IpAddr, usize)> = Vec::new();
+    for ip in addresses {
+        match votes.iter_mut().find(|(v, _)| v == ip) {
+            Some((_, count)) => *count += 1,
+            None => votes.push((*ip, 1)),
+        }
+    }
+    match votes.iter().max_by_key(|(_, count)| *count) {
+        Some((ip, count)) if *count >= quorum => Ok(*ip),
+        _ => Err(votes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(url: &str, family: Option<Family>) -> Endpoint {
+        Endpoint {
+            url: url.to_string(),
+            format: ResponseFormat::Text,
+            family,
+        }
+    }
+
+    #[test]
+    fn extracts_addresses() {
+        let json = r#"{"data": {"ip": " 203.0.113.7 "}, "list": [{"ip": "2001:db8::1"}]}"#;
+        let cases = [
+            (ResponseFormat::Text, " 203.0.113.7\n", "203.0.113.7"),
+            (ResponseFormat::Json("data.ip".to_string()), json, "203.0.113.7"),
+            (ResponseFormat::Json("list.0.ip".to_string()), json, "2001:db8::1"),
+            (ResponseFormat::Regex(r"(?m)^ip=(.+)$".to_string()), "fl=1\nip=198.51.100.4\n", "198.51.100.4"),
+            (ResponseFormat::Regex(r"\d+\.\d+\.\d+\.\d+".to_string()), "addr: 192.0.2.9.", "192.0.2.9"),
+        ];
+        for (format, body, expected) in cases {
+            assert_eq!(format.extract(body).unwrap(), expected, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn rejects_missing_addresses() {
+        let cases = [
+            (ResponseFormat::Json("data.ip".to_string()), r#"{"data": {}}"#),
+            (ResponseFormat::Json("data.ip".to_string()), r#"{"data": {"ip": 4}}"#),
+            (ResponseFormat::Json("ip".to_string()), "not json"),
+            (ResponseFormat::Regex(r"ip=(\S+)".to_string()), "nothing here"),
+        ];
+        for (format, body) in cases {
+            assert!(format.extract(body).is_err(), "{:?} on {}", format, body);
+        }
+    }
+
+    #[test]
+    fn counts_votes() {
+        let a: IpAddr = "203.0.113.1".parse().unwrap();
+        let b: IpAddr = "203.0.113.2".parse().unwrap();
+        let cases: [(&[IpAddr], usize, Option<IpAddr>); 5] = [
+            (&[a, a, b], 2, Some(a)),
+            (&[b, a, a], 2, Some(a)),
+            (&[a], 1, Some(a)),
+            (&[a, b], 2, None),
+            (&[], 1, None),
+        ];
+        for (addresses, quorum, expected) in cases {
+            assert_eq!(consensus(addresses, quorum).ok(), expected, "{:?} with quorum {}", addresses, quorum);
+        }
+        assert_eq!(consensus(&[a, b, a], 3), Err(vec![(a, 2), (b, 1)]));
+    }
+
+    #[test]
+    fn selects_endpoints_per_family() {
+        let source = HttpSource {
+            endpoints: vec![
+                endpoint("https://a", Some(Family::V4)),
+                endpoint("https://b", Some(Family::V4)),
+                endpoint("https://c", Some(Family::V6)),
+                endpoint("https://d", None),
+            ],
+            strategy: Strategy::Consensus(None),
+        };
+        let urls = |family| -> Vec<&str> { source.endpoints_for(family).iter().map(|e| e.url.as_str()).collect() };
+        assert_eq!(urls(Family::V4), ["https://a", "https://b", "https://d"]);
+        assert_eq!(urls(Family::V6), ["https://c", "https://d"]);
+    }
+
+    #[test]
+    fn defaults_quorum_per_family() {
+        // (IPv4 endpoints, IPv6 endpoints, configured quorum, IPv4 quorum, IPv6 quorum)
+        let cases = [
+            (2, 2, None, 2, 2),
+            (3, 1, None, 2, 1),
+            (5, 2, None, 3, 2),
+            (3, 3, Some(2), 2, 2),
+        ];
+        for (v4, v6, quorum, v4_quorum, v6_quorum) in cases {
+            let endpoints = (0..v4)
+                .map(|i| endpoint(&format!("https://v4-{}", i), Some(Family::V4)))
+                .chain((0..v6).map(|i| endpoint(&format!("https://v6-{}", i), Some(Family::V6))))
+                .collect();
+            let source = HttpSource {
+                endpoints,
+                strategy: Strategy::Consensus(quorum),
+            };
+            assert_eq!(source.quorum(Family::V4), v4_quorum, "{} IPv4 endpoints, quorum {:?}", v4, quorum);
+            assert_eq!(source.quorum(Family::V6), v6_quorum, "{} IPv6 endpoints, quorum {:?}", v6, quorum);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::{is_shared_address, Family};
+use crate::{error, logging};
+
+/// Public IP discovery from the addresses assigned to a local network interface
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterfaceSource {
+    pub name: String,
+}
+
+impl InterfaceSource {
+    /// Returns the first globally routable address of `family` on the interface.
+    ///
+    /// Private, carrier-grade NAT, loopback, link-local and unique local addresses are skipped, as are
+    /// temporary (privacy extension), deprecated and tentative IPv6 addresses where
+    /// the platform reports them.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the interface does not exist or has no suitable address of `family`.
+    pub fn fetch(&self, family: Family) -> Result<IpAddr, error::DynamicError> {
+        let logger = logging::Logger::new();
+        let addresses = interface_addresses(&self.name)?;
+        let unstable = unstable_ipv6_addresses(&self.name);
+        for ip in addresses {
+            if !family.matches(&ip) {
+                continue;
+            }
+            if !is_global(&ip) {
+                logger.debug(&format!("Skipping non-public address {} on {}", ip, self.name));
+                continue;
+            }
+            if unstable.contains(&ip) {
+                logger.debug(&format!("Skipping temporary address {} on {}", ip, self.name));
+                continue;
+            }
+            return Ok(ip);
+        }
+        Err(format!("interface {} has no public {} address", self.name, family).into())
+    }
+}
+
+fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_global_v4(v4),
+        IpAddr::V6(v6) => is_global_v6(v6),
+    }
+}
+
+fn is_global_v4(ip: &Ipv4Addr) -> bool {
+    // 192.0.0.0/24 holds protocol assignments such as the DS-Lite B4 address
+    let protocol_assignment = ip.octets()[..3] == [192, 0, 0];
+    !(ip.is_private()
+        || is_shared_address(ip)
+        || protocol_assignment
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast())
+}
+
+fn is_global_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    let link_local = first & 0xffc0 == 0xfe80;
+    let unique_local = first & 0xfe00 == 0xfc00;
+    let documentation = first == 0x2001 && ip.segments()[1] == 0x0db8;
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || link_local
+        || unique_local
+        || documentation)
+}
+
+/// Lists every IPv4 and IPv6 address assigned to the interface `name` using getifaddrs(3).
+#[cfg(unix)]
+fn interface_addresses(name: &str) -> Result<Vec<IpAddr>, error::DynamicError> {
+    use std::ffi::CStr;
+
+    let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        return Err(format!("getifaddrs failed: {}", std::io::Error::last_os_error()).into());
+    }
+
+    let mut found = false;
+    let mut addresses = Vec::new();
+    let mut cursor = head;
+    while !cursor.is_null() {
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+        let ifa_name = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        if ifa_name.to_bytes() != name.as_bytes() {
+            continue;
+        }
+        found = true;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))));
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                addresses.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(head) };
+
+    if !found {
+        return Err(format!("no network interface named '{}'", name).into());
+    }
+    Ok(addresses)
+}
+
+#[cfg(not(unix))]
+fn interface_addresses(_name: &str) -> Result<Vec<IpAddr>, error::DynamicError> {
+    Err("the interface IP source is not supported on this platform".into())
+}
+
+/// Lists the temporary, deprecated, tentative and DAD-failed IPv6 addresses of the
+/// interface `name`, read from `/proc/net/if_inet6`.
+#[cfg(target_os = "linux")]
+fn unstable_ipv6_addresses(name: &str) -> Vec<IpAddr> {
+    let contents = std::fs::read_to_string("/proc/net/if_inet6").unwrap_or_default();
+    parse_unstable_ipv6_addresses(&contents, name)
+}
+
+#[cfg(target_os = "linux")]
+fn parse_unstable_ipv6_addresses(contents: &str, name: &str) -> Vec<IpAddr> {
+    const IFA_F_TEMPORARY: u32 = 0x01;
+    const IFA_F_DADFAILED: u32 = 0x08;
+    const IFA_F_DEPRECATED: u32 = 0x20;
+    const IFA_F_TENTATIVE: u32 = 0x40;
+    const UNSTABLE: u32 = IFA_F_TEMPORARY | IFA_F_DADFAILED | IFA_F_DEPRECATED | IFA_F_TENTATIVE;
+
+    let mut addresses = Vec::new();
+    for line in contents.lines() {
+        // address ifindex prefixlen scope flags name
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || fields[5] != name || fields[0].len() != 32 {
+            continue;
+        }
+        let flags = u32::from_str_radix(fields[4], 16).unwrap_or(0);
+        if flags & UNSTABLE == 0 {
+            continue;
+        }
+        if let Ok(bits) = u128::from_str_radix(fields[0], 16) {
+            addresses.push(IpAddr::V6(Ipv6Addr::from(bits)));
+        }
+    }
+    addresses
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unstable_ipv6_addresses(_name: &str) -> Vec<IpAddr> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn global(ip: &str) -> bool {
+        is_global(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn accepts_public_addresses() {
+        assert!(global("1.1.1.1"));
+        assert!(global("81.2.69.160"));
+        assert!(global("100.128.0.1"));
+        assert!(global("192.0.1.1"));
+        assert!(global("2606:4700:4700::1111"));
+        assert!(global("2a00:1450:4001:80b::200e"));
+    }
+
+    #[test]
+    fn skips_non_public_addresses() {
+        for ip in [
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.20",
+            "100.64.0.1",
+            "100.127.255.254",
+            "192.0.0.2",
+            "127.0.0.1",
+            "169.254.1.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "192.0.2.1",
+            "224.0.0.251",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd12:3456:789a::1",
+            "2001:db8::1",
+            "ff02::1",
+        ] {
+            assert!(!global(ip), "{} counted as public", ip);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parses_unstable_ipv6_addresses() {
+        let contents = "\
+2a0011112222333344445555666677ff 02 40 00 80     eth0
+2a0011112222333344445555666677fe 02 40 00 01     eth0
+2a0011112222333344445555666677fd 02 40 00 20     eth0
+2a0011112222333344445555666677fc 02 40 00 c0     eth0
+2a0011112222333344445555666677fb 03 40 00 01     wlan0
+00000000000000000000000000000001 01 80 10 80        lo
+";
+        // Temporary, deprecated and tentative; permanent ones (0x80) are stable
+        let addresses: Vec<IpAddr> = [
+            "2a00:1111:2222:3333:4444:5555:6666:77fe",
+            "2a00:1111:2222:3333:4444:5555:6666:77fd",
+            "2a00:1111:2222:3333:4444:5555:6666:77fc",
+        ]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+        assert_eq!(parse_unstable_ipv6_addresses(contents, "eth0"), addresses);
+        assert!(parse_unstable_ipv6_addresses(contents, "lo").is_empty());
+    }
+}
@@ -0,0 +1,502 @@
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, time};
+
+use super::{is_shared_address, Family};
+use crate::{error, logging};
+
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const NAT_PMP_PORT: u16 = 5351;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// A protocol for asking the gateway for its external address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouterProtocol {
+    Upnp,
+    NatPmp,
+    Pcp,
+}
+
+impl Display for RouterProtocol {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            RouterProtocol::Upnp => write!(f, "UPnP"),
+            RouterProtocol::NatPmp => write!(f, "NAT-PMP"),
+            RouterProtocol::Pcp => write!(f, "PCP"),
+        }
+    }
+}
+
+impl FromStr for RouterProtocol {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<RouterProtocol, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "upnp" => Ok(RouterProtocol::Upnp),
+            "natpmp" | "nat-pmp" => Ok(RouterProtocol::NatPmp),
+            "pcp" => Ok(RouterProtocol::Pcp),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Public IP discovery by asking the home router (internet gateway) for its external address
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouterSource {
+    /// Tried in order until one answers
+    pub protocols: Vec<RouterProtocol>,
+    /// NAT-PMP/PCP server; the default gateway on port 5351 when `None`
+    pub gateway: Option<SocketAddr>,
+    /// Where SSDP discovery requests are sent; the UPnP multicast group when `None`
+    pub ssdp_address: Option<SocketAddr>,
+}
+
+impl Default for RouterSource {
+    fn default() -> Self {
+        Self {
+            protocols: vec![RouterProtocol::Upnp, RouterProtocol::NatPmp, RouterProtocol::Pcp],
+            gateway: None,
+            ssdp_address: None,
+        }
+    }
+}
+
+impl RouterSource {
+    /// Retrieves the gateway's external IPv4 address, trying each configured protocol in turn.
+    ///
+    /// Logs a warning when the gateway reports an address in the shared address space
+    /// (100.64.0.0/10), which means the ISP uses carrier-grade NAT and the address is not
+    /// reachable from the internet.
+    ///
+    /// # Errors
+    ///
+    /// Fails for IPv6 (gateways only report their IPv4 WAN address), or when no protocol
+    /// gets an answer.
+    pub async fn fetch(&self, family: Family) -> Result<IpAddr, error::DynamicError> {
+        let logger = logging::Logger::new();
+        if family == Family::V6 {
+            return Err("the router IP source only reports IPv4 addresses".into());
+        }
+
+        let mut last_err: error::DynamicError = "no router protocols configured".into();
+        for protocol in &self.protocols {
+            let result = match protocol {
+                RouterProtocol::Upnp => self.query_upnp().await,
+                RouterProtocol::NatPmp => self.query_nat_pmp().await,
+                RouterProtocol::Pcp => self.query_pcp().await,
+            };
+            match result {
+                Ok(ip) => {
+                    logger.debug(&format!("{} gateway reported external address '{}'", protocol, ip));
+                    if is_shared_address(&ip) {
+                        logger.warning(&format!(
+                            "The router reports {}, which is in carrier-grade NAT space (100.64.0.0/10); \
+                             this is not your public address. Use an http ip_source instead.",
+                            ip
+                        ));
+                    }
+                    return Ok(IpAddr::V4(ip));
+                }
+                Err(err) => {
+                    logger.warning(&format!("{} query to the router failed: {}", protocol, err));
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn gateway(&self) -> Result<SocketAddr, error::DynamicError> {
+        match self.gateway {
+            Some(gateway) => Ok(gateway),
+            None => Ok(SocketAddr::new(IpAddr::V4(default_gateway()?), NAT_PMP_PORT)),
+        }
+    }
+
+    /// Discovers the internet gateway device with SSDP and calls `GetExternalIPAddress`.
+    async fn query_upnp(&self) -> Result<Ipv4Addr, error::DynamicError> {
+        let ssdp_address = match self.ssdp_address {
+            Some(address) => address,
+            None => SocketAddr::from_str(SSDP_ADDRESS)?,
+        };
+        let location = discover_gateway_location(ssdp_address).await?;
+        let client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+        let description = client.get(location.clone()).send().await?.text().await?;
+
+        let (service, control_url) = find_wan_service(&description)
+            .ok_or("the gateway does not offer a WANIPConnection or WANPPPConnection service")?;
+        let base = match xml_tag(&description, "URLBase") {
+            Some(base) => reqwest::Url::parse(base.trim())?,
+            None => location,
+        };
+        let control_url = base.join(&control_url)?;
+
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+<s:Body><u:GetExternalIPAddress xmlns:u=\"{service}\"></u:GetExternalIPAddress></s:Body>\
+</s:Envelope>"
+        );
+        let response = client
+            .post(control_url)
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{}#GetExternalIPAddress\"", service))
+            .body(body)
+            .send()
+            .await?
+            .text()
+            .await?;
+        let address = xml_tag(&response, "NewExternalIPAddress")
+            .ok_or("GetExternalIPAddress response did not contain NewExternalIPAddress")?;
+        Ok(Ipv4Addr::from_str(address.trim())?)
+    }
+
+    /// Sends a NAT-PMP (RFC 6886) external address request to the gateway.
+    async fn query_nat_pmp(&self) -> Result<Ipv4Addr, error::DynamicError> {
+        let gateway = self.gateway()?;
+        let response = udp_request(gateway, &[0, 0], 12).await?;
+        parse_nat_pmp_response(&response)
+    }
+
+    /// Creates a short-lived PCP (RFC 6887) MAP mapping to learn the assigned external
+    /// address, then deletes it again.
+    async fn query_pcp(&self) -> Result<Ipv4Addr, error::DynamicError> {
+        let gateway = self.gateway()?;
+        let client_ip = local_address_towards(gateway).await?;
+        let nonce = pcp_nonce();
+
+        let response = udp_request(gateway, &pcp_map_request(client_ip, &nonce, 120), 60).await?;
+        let external = parse_pcp_response(&response, &nonce)?;
+
+        // Best effort: remove the mapping again
+        let _ = udp_request(gateway, &pcp_map_request(client_ip, &nonce, 0), 60).await;
+        Ok(external)
+    }
+}
+
+/// A mapping nonce that only needs to be unpredictable enough to match our own responses.
+fn pcp_nonce() -> [u8; 12] {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let seed = nanos ^ ((std::process::id() as u128) << 64);
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&seed.to_le_bytes()[..12]);
+    nonce
+}
+
+/// Builds a PCP MAP request for UDP port 9 (discard) with the given lifetime.
+fn pcp_map_request(client_ip: IpAddr, nonce: &[u8; 12], lifetime: u32) -> Vec<u8> {
+    let client_ip = match client_ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    let mut packet = Vec::with_capacity(60);
+    packet.extend_from_slice(&[2, 1, 0, 0]);
+    packet.extend_from_slice(&lifetime.to_be_bytes());
+    packet.extend_from_slice(&client_ip.octets());
+    packet.extend_from_slice(nonce);
+    packet.extend_from_slice(&[17, 0, 0, 0]);
+    packet.extend_from_slice(&9u16.to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    packet
+}
+
+/// Reads the external address from a NAT-PMP external address response.
+fn parse_nat_pmp_response(response: &[u8]) -> Result<Ipv4Addr, error::DynamicError> {
+    if response.len() < 12 {
+        return Err(format!("NAT-PMP response too short ({} bytes)", response.len()).into());
+    }
+    if response[0] != 0 || response[1] != 128 {
+        return Err("unexpected NAT-PMP response".into());
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(format!("NAT-PMP result code {}", result).into());
+    }
+    Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+}
+
+/// Reads the assigned external address from a PCP MAP response to the request with `nonce`.
+fn parse_pcp_response(response: &[u8], nonce: &[u8; 12]) -> Result<Ipv4Addr, error::DynamicError> {
+    if response.len() < 60 {
+        return Err(format!("PCP response too short ({} bytes)", response.len()).into());
+    }
+    if response[0] != 2 || response[1] != 0x81 {
+        return Err("unexpected PCP response".into());
+    }
+    if response[3] != 0 {
+        return Err(format!("PCP result code {}", response[3]).into());
+    }
+    if response[24..36] != nonce[..] {
+        return Err("PCP response nonce does not match the request".into());
+    }
+    let mut external = [0u8; 16];
+    external.copy_from_slice(&response[44..60]);
+    Ok(Ipv6Addr::from(external)
+        .to_ipv4_mapped()
+        .ok_or("PCP server assigned a non-IPv4 external address")?)
+}
+
+/// Sends `request` to `server` over UDP and waits for a reply of at least `min_len`
+/// bytes, retrying with the RFC 6886 back-off (250ms, doubling) up to four times.
+async fn udp_request(server: SocketAddr, request: &[u8], min_len: usize) -> Result<Vec<u8>, error::DynamicError> {
+    let socket = UdpSocket::bind(unspecified_towards(server)).await?;
+    socket.connect(server).await?;
+    let mut wait = Duration::from_millis(250);
+    let mut buf = [0u8; 1100];
+    for _ in 0..4 {
+        socket.send(request).await?;
+        if let Ok(received) = time::timeout(wait, socket.recv(&mut buf)).await {
+            let len = received?;
+            if len >= min_len {
+                return Ok(buf[..len].to_vec());
+            }
+        }
+        wait *= 2;
+    }
+    Err(format!("no response from {}", server).into())
+}
+
+/// Returns the local address used to reach `server`.
+async fn local_address_towards(server: SocketAddr) -> Result<IpAddr, error::DynamicError> {
+    let socket = UdpSocket::bind(unspecified_towards(server)).await?;
+    socket.connect(server).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The wildcard address of the same family as `server` to bind a socket to.
+fn unspecified_towards(server: SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Sends an SSDP M-SEARCH for internet gateway devices and returns the `LOCATION`
+/// of the first device description that answers.
+async fn discover_gateway_location(ssdp_address: SocketAddr) -> Result<reqwest::Url, error::DynamicError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+HOST: {}\r\n\
+MAN: \"ssdp:discover\"\r\n\
+MX: 2\r\n\
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        ssdp_address
+    );
+    socket.send_to(request.as_bytes(), ssdp_address).await?;
+
+    let mut buf = [0u8; 2048];
+    let deadline = time::Instant::now() + SSDP_TIMEOUT;
+    loop {
+        let (len, _) = match time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            Ok(received) => received?,
+            Err(_) => return Err("no UPnP internet gateway device answered".into()),
+        };
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let location = response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("location") {
+                Some(value.trim().to_string())
+            } else {
+                None
+            }
+        });
+        if let Some(location) = location {
+            return Ok(reqwest::Url::parse(&location)?);
+        }
+    }
+}
+
+/// Finds the first WAN connection service in a device description, returning its
+/// service type and control URL.
+fn find_wan_service(description: &str) -> Option<(String, String)> {
+    let services: Vec<(String, String)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|block| {
+            let service_type = xml_tag(block, "serviceType")?;
+            let control_url = xml_tag(block, "controlURL")?;
+            Some((service_type.trim().to_string(), control_url.trim().to_string()))
+        })
+        .collect();
+    WAN_SERVICES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(service_type, _)| service_type == wanted)
+            .cloned()
+    })
+}
+
+fn xml_tag<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&close)?;
+    Some(&xml[start..start + end])
+}
+
+/// Reads the default IPv4 gateway from `/proc/net/route`.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Result<Ipv4Addr, error::DynamicError> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    for line in routes.lines().skip(1) {
+        // Iface Destination Gateway Flags ...
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            continue;
+        }
+        // The kernel prints the network-order address as a native integer
+        let gateway = u32::from_str_radix(fields[2], 16)?;
+        return Ok(Ipv4Addr::from(gateway.to_ne_bytes()));
+    }
+    Err("no default IPv4 route found; set 'gateway' in ip_source".into())
+}
+
+/// Reads the default IPv4 gateway from `route -n get default`.
+#[cfg(target_os = "macos")]
+fn default_gateway() -> Result<Ipv4Addr, error::DynamicError> {
+    let output = std::process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    for line in output.lines() {
+        if let Some(gateway) = line.trim().strip_prefix("gateway:") {
+            return Ok(Ipv4Addr::from_str(gateway.trim())?);
+        }
+    }
+    Err("no default IPv4 route found; set 'gateway' in ip_source".into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn default_gateway() -> Result<Ipv4Addr, error::DynamicError> {
+    Err("cannot detect the default gateway on this platform; set 'gateway' in ip_source".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    /// A PCP MAP response (RFC 6887 7.2, 11.1) for `NONCE` assigning `external`
+    fn pcp_response(result: u8, nonce: &[u8; 12], external: Ipv6Addr) -> Vec<u8> {
+        let mut response = vec![2, 0x81, 0, result];
+        response.extend_from_slice(&120u32.to_be_bytes());
+        // Epoch time and reserved
+        response.extend_from_slice(&[0; 16]);
+        response.extend_from_slice(nonce);
+        response.extend_from_slice(&[17, 0, 0, 0, 0, 9, 0xc3, 0x50]);
+        response.extend_from_slice(&external.octets());
+        response
+    }
+
+    #[test]
+    fn parses_nat_pmp_response() {
+        let response = [0, 128, 0, 0, 0, 0, 0x1c, 0x20, 198, 51, 100, 7];
+        assert_eq!(parse_nat_pmp_response(&response).unwrap(), Ipv4Addr::new(198, 51, 100, 7));
+    }
+
+    #[test]
+    fn rejects_nat_pmp_errors() {
+        // Result code 3: network failure, e.g. no external address yet
+        let response = [0, 128, 0, 3, 0, 0, 0x1c, 0x20, 0, 0, 0, 0];
+        assert!(parse_nat_pmp_response(&response).is_err());
+        // A reply to another opcode
+        let response = [0, 129, 0, 0, 0, 0, 0x1c, 0x20, 198, 51, 100, 7];
+        assert!(parse_nat_pmp_response(&response).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_responses() {
+        let response = [0, 128, 0, 0, 0, 0, 0x1c, 0x20, 198, 51, 100, 7];
+        for len in 0..response.len() {
+            assert!(parse_nat_pmp_response(&response[..len]).is_err());
+        }
+        let response = pcp_response(0, &NONCE, Ipv4Addr::new(203, 0, 113, 9).to_ipv6_mapped());
+        for len in 0..response.len() {
+            assert!(parse_pcp_response(&response[..len], &NONCE).is_err());
+        }
+    }
+
+    #[test]
+    fn binds_to_gateway_family() {
+        let v4: SocketAddr = "192.168.1.1:5351".parse().unwrap();
+        assert_eq!(unspecified_towards(v4), "0.0.0.0:0".parse().unwrap());
+        let v6: SocketAddr = "[fe80::1]:5351".parse().unwrap();
+        assert_eq!(unspecified_towards(v6), "[::]:0".parse().unwrap());
+    }
+
+    #[test]
+    fn builds_pcp_map_request() {
+        let request = pcp_map_request(Ipv4Addr::new(192, 168, 1, 20).into(), &NONCE, 120);
+        assert_eq!(request.len(), 60);
+        assert_eq!(&request[..8], &[2, 1, 0, 0, 0, 0, 0, 120]);
+        assert_eq!(&request[8..24], &Ipv4Addr::new(192, 168, 1, 20).to_ipv6_mapped().octets());
+        assert_eq!(&request[24..36], &NONCE);
+        assert_eq!(&request[36..42], &[17, 0, 0, 0, 0, 9]);
+
+        let client_ip: Ipv6Addr = "2001:db8::20".parse().unwrap();
+        let request = pcp_map_request(client_ip.into(), &NONCE, 120);
+        assert_eq!(&request[8..24], &client_ip.octets());
+    }
+
+    #[test]
+    fn parses_pcp_response() {
+        let external = Ipv4Addr::new(203, 0, 113, 9);
+        let response = pcp_response(0, &NONCE, external.to_ipv6_mapped());
+        assert_eq!(parse_pcp_response(&response, &NONCE).unwrap(), external);
+    }
+
+    #[test]
+    fn rejects_pcp_errors() {
+        let external = Ipv4Addr::new(203, 0, 113, 9).to_ipv6_mapped();
+        // Result code 2: NOT_AUTHORIZED
+        assert!(parse_pcp_response(&pcp_response(2, &NONCE, external), &NONCE).is_err());
+        assert!(parse_pcp_response(&pcp_response(0, &[0; 12], external), &NONCE).is_err());
+        let native = "2001:db8::1".parse().unwrap();
+        assert!(parse_pcp_response(&pcp_response(0, &NONCE, native), &NONCE).is_err());
+    }
+
+    #[test]
+    fn finds_wan_service() {
+        let description = "<root><device><serviceList>\
+<service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+<controlURL>/l3f</controlURL></service>\
+<service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>\
+<controlURL>/ppp</controlURL></service>\
+<service><serviceType> urn:schemas-upnp-org:service:WANIPConnection:1 </serviceType>\
+<controlURL> /ctl/IPConn </controlURL></service>\
+</serviceList></device></root>";
+        assert_eq!(
+            find_wan_service(description),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1".to_string(),
+                "/ctl/IPConn".to_string()
+            ))
+        );
+        assert_eq!(find_wan_service("<root></root>"), None);
+    }
+
+    #[test]
+    fn detects_shared_addresses() {
+        assert!(is_shared_address(&Ipv4Addr::new(100, 64, 0, 1)));
+        assert!(is_shared_address(&Ipv4Addr::new(100, 127, 255, 254)));
+        assert!(!is_shared_address(&Ipv4Addr::new(100, 128, 0, 1)));
+        assert!(!is_shared_address(&Ipv4Addr::new(100, 63, 255, 255)));
+    }
+}
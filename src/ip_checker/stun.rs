@@ -0,0 +1,257 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, time};
+
+use super::Family;
+use crate::{error, logging};
+
+const DEFAULT_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun.cloudflare.com:3478"];
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTEMPTS: usize = 4;
+
+/// Public IP discovery with a STUN (RFC 5389) Binding Request
+#[derive(Debug, Clone, PartialEq)]
+pub struct StunSource {
+    /// `host:port` of the STUN servers, tried in order
+    pub servers: Vec<String>,
+}
+
+impl Default for StunSource {
+    fn default() -> Self {
+        Self {
+            servers: DEFAULT_SERVERS.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl StunSource {
+    /// Sends a Binding Request to each server in turn and returns the reflexive address
+    /// from the first successful response.
+    ///
+    /// The request is sent over `family`, so the server reports the IPv4 or IPv6
+    /// address the host is seen from.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no server resolves to an address of `family` or none of them answers.
+    pub async fn fetch(&self, family: Family) -> Result<IpAddr, error::DynamicError> {
+        let logger = logging::Logger::new();
+        let mut last_err: error::DynamicError = format!("no STUN servers reachable over {}", family).into();
+        for server in &self.servers {
+            let addresses = match tokio::net::lookup_host(server.as_str()).await {
+                Ok(addresses) => addresses.filter(|a| family.matches(&a.ip())).collect::<Vec<_>>(),
+                Err(err) => {
+                    logger.warning(&format!("Could not resolve STUN server {}: {}", server, err));
+                    last_err = err.into();
+                    continue;
+                }
+            };
+            for address in addresses {
+                match binding_request(address).await {
+                    Ok(ip) if family.matches(&ip) => {
+                        logger.debug(&format!("STUN server {} ({}) returned '{}'", server, address, ip));
+                        return Ok(ip);
+                    }
+                    Ok(ip) => {
+                        last_err = format!("STUN server {} returned '{}', expected an {} address", server, ip, family).into();
+                    }
+                    Err(err) => {
+                        logger.warning(&format!("STUN request to {} ({}) failed: {}", server, address, err));
+                        last_err = err;
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// Sends a Binding Request to `server`, retransmitting with a doubling timeout, and
+/// returns the mapped address from the response.
+async fn binding_request(server: SocketAddr) -> Result<IpAddr, error::DynamicError> {
+    let bind_address = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(server).await?;
+
+    let transaction_id = transaction_id();
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+
+    let mut wait = Duration::from_millis(500);
+    let mut buf = [0u8; 1024];
+    for _ in 0..ATTEMPTS {
+        socket.send(&request).await?;
+        if let Ok(received) = time::timeout(wait, socket.recv(&mut buf)).await {
+            let len = received?;
+            if len >= 20 && buf[8..20] == transaction_id {
+                return parse_binding_response(&buf[..len], &transaction_id);
+            }
+        }
+        wait *= 2;
+    }
+    Err(format!("no STUN response from {}", server).into())
+}
+
+fn transaction_id() -> [u8; 12] {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let seed = nanos ^ ((std::process::id() as u128) << 64);
+    let mut id = [0u8; 12];
+    id.copy_from_slice(&seed.to_le_bytes()[..12]);
+    id
+}
+
+/// Extracts the XOR-MAPPED-ADDRESS (or, from older servers, MAPPED-ADDRESS) attribute.
+fn parse_binding_response(buf: &[u8], transaction_id: &[u8; 12]) -> Result<IpAddr, error::DynamicError> {
+    if buf.len() < 20 {
+        return Err("truncated STUN response".into());
+    }
+    if buf[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return Err("STUN response lacks the magic cookie".into());
+    }
+    let message_type = u16::from_be_bytes([buf[0], buf[1]]);
+    if message_type != BINDING_SUCCESS {
+        return Err(format!("unexpected STUN message type {:#06x}", message_type).into());
+    }
+    let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    let attributes = buf.get(20..20 + length).ok_or("truncated STUN response")?;
+
+    let mut mapped = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let kind = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let len = u16::from_be_bytes([attributes[offset + 2], attributes[offset + 3]]) as usize;
+        let value = attributes
+            .get(offset + 4..offset + 4 + len)
+            .ok_or("truncated STUN attribute")?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return decode_address(value, Some(transaction_id)),
+            MAPPED_ADDRESS => mapped = Some(decode_address(value, None)?),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of four bytes
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+    mapped.ok_or_else(|| "STUN response has no mapped address".into())
+}
+
+/// Decodes a (XOR-)MAPPED-ADDRESS value; `transaction_id` is given for the XOR variant.
+fn decode_address(value: &[u8], transaction_id: Option<&[u8; 12]>) -> Result<IpAddr, error::DynamicError> {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    match (value.get(1), value.len()) {
+        (Some(0x01), 8) => {
+            let mut octets = [value[4], value[5], value[6], value[7]];
+            if transaction_id.is_some() {
+                for (octet, mask) in octets.iter_mut().zip(cookie) {
+                    *octet ^= mask;
+                }
+            }
+            Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        (Some(0x02), 20) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&value[4..20]);
+            if let Some(id) = transaction_id {
+                let mask = cookie.iter().chain(id.iter());
+                for (octet, mask) in octets.iter_mut().zip(mask) {
+                    *octet ^= mask;
+                }
+            }
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => Err("malformed STUN address attribute".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The transaction ID of the sample responses in RFC 5769
+    const TRANSACTION_ID: [u8; 12] = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+
+    fn response(message_type: u16, attributes: &[u8]) -> Vec<u8> {
+        let mut buf = message_type.to_be_bytes().to_vec();
+        buf.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&TRANSACTION_ID);
+        buf.extend_from_slice(attributes);
+        buf
+    }
+
+    #[test]
+    fn decodes_xor_mapped_ipv4() {
+        // RFC 5769 2.2: 192.0.2.1 port 32853
+        let attributes = [0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+        let ip = parse_binding_response(&response(BINDING_SUCCESS, &attributes), &TRANSACTION_ID).unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[test]
+    fn decodes_xor_mapped_ipv6() {
+        // RFC 5769 2.3: 2001:db8:1234:5678:11:2233:4455:6677 port 32853
+        let attributes = [
+            0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25,
+            0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        ];
+        let ip = parse_binding_response(&response(BINDING_SUCCESS, &attributes), &TRANSACTION_ID).unwrap();
+        assert_eq!(ip, "2001:db8:1234:5678:11:2233:4455:6677".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn skips_padded_attributes() {
+        // SOFTWARE "abcde", padded to eight bytes, before MAPPED-ADDRESS 203.0.113.5
+        let attributes = [
+            0x80, 0x22, 0x00, 0x05, b'a', b'b', b'c', b'd', b'e', 0, 0, 0, 0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x0d,
+            0x96, 203, 0, 113, 5,
+        ];
+        let ip = parse_binding_response(&response(BINDING_SUCCESS, &attributes), &TRANSACTION_ID).unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5)));
+    }
+
+    #[test]
+    fn prefers_xor_mapped_address() {
+        let attributes = [
+            0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x0d, 0x96, 10, 0, 0, 1, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1,
+            0x47, 0xe1, 0x12, 0xa6, 0x43,
+        ];
+        let ip = parse_binding_response(&response(BINDING_SUCCESS, &attributes), &TRANSACTION_ID).unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[test]
+    fn rejects_bad_responses() {
+        let attributes = [0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+        // Binding Error Response
+        assert!(parse_binding_response(&response(0x0111, &attributes), &TRANSACTION_ID).is_err());
+        // No address attribute
+        assert!(parse_binding_response(&response(BINDING_SUCCESS, &[]), &TRANSACTION_ID).is_err());
+        // Attribute longer than the message
+        let mut buf = response(BINDING_SUCCESS, &attributes[..8]);
+        buf[3] = 12;
+        assert!(parse_binding_response(&buf, &TRANSACTION_ID).is_err());
+        // RFC 3489 server without the magic cookie
+        let mut buf = response(BINDING_SUCCESS, &attributes);
+        buf[4..8].copy_from_slice(&[0; 4]);
+        assert!(parse_binding_response(&buf, &TRANSACTION_ID).is_err());
+        // Shorter than a STUN header
+        assert!(parse_binding_response(&response(BINDING_SUCCESS, &[])[..12], &TRANSACTION_ID).is_err());
+        // Unknown address family
+        assert!(decode_address(&[0, 3, 0, 0, 1, 2, 3, 4], None).is_err());
+    }
+}
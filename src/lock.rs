@@ -0,0 +1,67 @@
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+/// An advisory lock (flock on Unix, LockFileEx on Windows) on the lock file of one config
+/// file, released when dropped or when the process exits
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+}
+
+/// The outcome of trying to take the lock
+#[derive(Debug)]
+pub enum Acquired {
+    Locked(RunLock),
+    /// Another process holds the lock; its PID, if it recorded one
+    Held(Option<u32>),
+}
+
+/// Returns the lock file for `config_file`: `run-<hash>.lock` in the state directory,
+/// where the hash is of the config file's canonical path.
+pub fn lock_path(config_file: &str) -> PathBuf {
+    let canonical = fs::canonicalize(config_file).unwrap_or_else(|_| PathBuf::from(config_file));
+    crate::state::state_dir().join(format!("run-{:016x}.lock", fnv1a(canonical.to_string_lossy().as_bytes())))
+}
+
+/// Takes the lock at `path`. With `wait`, blocks until the holder releases it; otherwise
+/// returns `Acquired::Held` straight away.
+pub fn acquire(path: &Path, wait: bool) -> io::Result<Acquired> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Not truncated on open: the holder's PID must survive until the lock is ours
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) if wait => file.lock()?,
+        Err(TryLockError::WouldBlock) => return Ok(Acquired::Held(holder(&mut file))),
+        Err(TryLockError::Error(err)) => return Err(err),
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    Ok(Acquired::Locked(RunLock { _file: file }))
+}
+
+/// Reads the PID recorded by the process holding the lock.
+fn holder(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across Rust releases
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -1,15 +1,26 @@
-use std::future::Future;
+use std::{future::Future, path::Path};
 use futures::future;
 use api_client::APIClient;
-use arg_parser::{Args, Commands};
+use arg_parser::{Args, Commands, ConfigCommands, IfLocked};
 use clap::Parser;
 
 mod api_client;
 mod arg_parser;
+mod config;
+mod config_tools;
+mod config_watch;
+mod daemon;
 mod error;
 mod installer;
 mod ip_checker;
+mod lock;
 mod logging;
+mod metrics;
+mod netlink;
+mod resolver;
+mod state;
+mod status;
+mod systemd;
 mod time_tools;
 
 const DEFAULT_CONFIG_FILE: &str = ".ddns.conf";
@@ -28,33 +39,150 @@ async fn log_and_ignore_errors<F>(fut: F)
     }
 }
 
+/// Runs every entry concurrently, logging the errors of each.
+async fn execute_all(config: &[APIClient]) {
+    let mut futures = Vec::new();
+    for protocol in config.iter() {
+        futures.push(log_and_ignore_errors(protocol.execute()));
+    }
+    future::join_all(futures).await;
+}
+
+/// Writes the metrics to `path`, if one was given; a failure is logged but not fatal.
+fn write_metrics_file(path: Option<&Path>) {
+    if let Some(path) = path {
+        if let Err(err) = metrics::write_textfile(path) {
+            logging::Logger::new().warning(&format!(
+                "Could not write metrics file {}: {}",
+                path.display(),
+                err
+            ));
+        }
+    }
+}
+
+/// Takes the single-instance lock for `file`, so overlapping runs cannot race each other's
+/// updates. A one-shot run that finds it held skips, unless `if_locked` is `Wait`; the
+/// daemon exits with an error instead, since it has no later run to leave the work to.
+fn lock_or_exit(file: &str, if_locked: IfLocked, daemon: bool) -> Option<lock::RunLock> {
+    let logger = logging::Logger::new();
+    let path = lock::lock_path(file);
+    let holder = match lock::acquire(&path, false) {
+        Ok(lock::Acquired::Locked(lock)) => return Some(lock),
+        Ok(lock::Acquired::Held(holder)) => holder
+            .map(|pid| format!("pid {}", pid))
+            .unwrap_or_else(|| "another process".to_string()),
+        Err(err) => {
+            logger.warning(&format!("Could not take lock {}: {}; running unlocked", path.display(), err));
+            return None;
+        }
+    };
+    match if_locked {
+        IfLocked::Wait => {
+            logger.info(&format!(
+                "Another rust-ddns run ({}) is using {}; waiting for it to finish",
+                holder, file
+            ));
+            match lock::acquire(&path, true) {
+                Ok(lock::Acquired::Locked(lock)) => Some(lock),
+                Ok(lock::Acquired::Held(_)) => None,
+                Err(err) => {
+                    logger.warning(&format!("Could not take lock {}: {}; running unlocked", path.display(), err));
+                    None
+                }
+            }
+        }
+        IfLocked::Skip if daemon => {
+            logger.error(&format!(
+                "Another rust-ddns run ({}) is using {} (lock {}); not starting. Use --if-locked wait to wait for it",
+                holder,
+                file,
+                path.display()
+            ));
+            std::process::exit(1);
+        }
+        IfLocked::Skip => {
+            logger.warning(&format!(
+                "Another rust-ddns run ({}) is still using {} (lock {}); skipping this run",
+                holder,
+                file,
+                path.display()
+            ));
+            std::process::exit(0);
+        }
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), error::DynamicError> {
     let args = Args::parse();
 
     if let Some(cmd) = args.command {
         match cmd {
-            Commands::Install { interval, log_file, config_file } => {
-                installer::install(&interval, log_file.as_deref(), config_file.as_deref());
+            Commands::Install { interval, log_file, config_file, daemon } => {
+                installer::install(&interval, log_file.as_deref(), config_file.as_deref(), daemon);
             }
             Commands::Uninstall { purge } => {
                 installer::uninstall(purge);
             }
+            Commands::Config { command: ConfigCommands::Check } => {
+                let file = api_client::get_config_file_path(args.config_file);
+                if !config_tools::check(&file, args.config_format) {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Config { command: ConfigCommands::Init } => {
+                let file = api_client::get_config_file_path(args.config_file);
+                if let Err(err) = config_tools::init(&file, args.config_format) {
+                    logging::Logger::new().error(&format!("Could not create config entry in {}: {}", file, err));
+                    std::process::exit(1);
+                }
+            }
+            Commands::Daemon { interval, jitter, listen, health_threshold } => {
+                let logger = logging::Logger::new();
+                let parse = |name: &str, value: &str| {
+                    time_tools::parse_duration_secs(value).unwrap_or_else(|| {
+                        logger.error(&format!(
+                            "--{} must be a duration such as 5min, 30s or 1h (got '{}')",
+                            name, value
+                        ));
+                        std::process::exit(1);
+                    })
+                };
+                let options = daemon::Options {
+                    interval: parse("interval", &interval),
+                    jitter: parse("jitter", &jitter),
+                    listen,
+                    health_threshold: parse("health-threshold", &health_threshold),
+                    metrics_file: args.metrics_file,
+                    skip_invalid: args.skip_invalid,
+                    config_format: args.config_format,
+                };
+                if options.interval == 0 {
+                    logger.error("--interval must be greater than zero");
+                    std::process::exit(1);
+                }
+                let file = api_client::get_config_file_path(args.config_file);
+                let _lock = lock_or_exit(&file, args.if_locked, true);
+                return daemon::run(file, options).await;
+            }
         }
         return Ok(());
     }
 
     if args.ip {
-        println!("{}", ip_checker::IP::get_actual_ip().await?);
+        let checker = ip_checker::IP::new(ip_checker::IpSource::default());
+        println!("{}", checker.get_actual_ip(ip_checker::Family::V4).await?);
+        if let Ok(v6) = checker.get_actual_ip(ip_checker::Family::V6).await {
+            println!("{}", v6);
+        }
         return Ok(())
     }
 
     let file = api_client::get_config_file_path(args.config_file);
-    let mut config = APIClient::from_config_file(file).await;
-    let mut futures = Vec::new();
-    for protocol in config.iter_mut() {
-        futures.push(log_and_ignore_errors(protocol.execute()));
-    }
-    future::join_all(futures).await;
+    let _lock = lock_or_exit(&file, args.if_locked, false);
+    let (config, _) = APIClient::from_config_file(file, args.config_format, args.skip_invalid).await;
+    execute_all(&config).await;
+    write_metrics_file(args.metrics_file.as_deref());
     Ok(())
 }
@@ -0,0 +1,295 @@
+use std::{collections::BTreeMap, fmt::Write, fs, io, path::Path, sync::Mutex, time::Duration};
+
+/// Upper bounds, in seconds, of the latency histogram buckets
+const BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Value {
+    Number(f64),
+    Histogram { counts: [u64; BUCKETS.len()], sum: f64, count: u64 },
+}
+
+#[derive(Debug)]
+struct Metric {
+    help: &'static str,
+    kind: Kind,
+    /// Rendered label set (`{a="b"}`) to value
+    series: BTreeMap<String, Value>,
+}
+
+/// Every metric recorded by this process, by name
+static REGISTRY: Mutex<BTreeMap<&'static str, Metric>> = Mutex::new(BTreeMap::new());
+
+fn record(name: &'static str, help: &'static str, kind: Kind, labels: &[(&str, &str)], update: impl FnOnce(&mut Value)) {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let metric = registry.entry(name).or_insert_with(|| Metric {
+        help,
+        kind,
+        series: BTreeMap::new(),
+    });
+    let value = metric.series.entry(render_labels(labels)).or_insert_with(|| match kind {
+        Kind::Histogram => Value::Histogram {
+            counts: [0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        },
+        _ => Value::Number(0.0),
+    });
+    update(value);
+}
+
+fn increment(name: &'static str, help: &'static str, labels: &[(&str, &str)]) {
+    record(name, help, Kind::Counter, labels, |value| {
+        if let Value::Number(n) = value {
+            *n += 1.0;
+        }
+    });
+}
+
+fn set(name: &'static str, help: &'static str, labels: &[(&str, &str)], new: f64) {
+    record(name, help, Kind::Gauge, labels, |value| {
+        if let Value::Number(n) = value {
+            *n = new;
+        }
+    });
+}
+
+fn observe(name: &'static str, help: &'static str, labels: &[(&str, &str)], duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    record(name, help, Kind::Histogram, labels, |value| {
+        if let Value::Histogram { counts, sum, count } = value {
+            for (bucket, bound) in counts.iter_mut().zip(BUCKETS) {
+                if seconds <= bound {
+                    *bucket += 1;
+                }
+            }
+            *sum += seconds;
+            *count += 1;
+        }
+    });
+}
+
+fn result_label(ok: bool) -> &'static str {
+    if ok {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+/// An attempt to determine the actual address of `family` through `source`.
+pub fn ip_check(source: &str, family: &str, ok: bool, duration: Duration) {
+    let labels = [("source", source), ("family", family)];
+    increment(
+        "ddns_ip_checks_total",
+        "Attempts to determine the actual IP address.",
+        &[labels[0], labels[1], ("result", result_label(ok))],
+    );
+    observe(
+        "ddns_ip_check_duration_seconds",
+        "Time taken to determine the actual IP address.",
+        &labels,
+        duration,
+    );
+}
+
+/// A record found to differ from the actual address.
+pub fn ip_change(domain: &str, record: &str) {
+    increment(
+        "ddns_ip_changes_total",
+        "Records whose published address differed from the actual address.",
+        &[("domain", domain), ("record", record)],
+    );
+}
+
+/// The outcome of pushing a record to `provider`.
+pub fn update(provider: &str, domain: &str, record: &str, ok: bool) {
+    increment(
+        "ddns_updates_total",
+        "Record updates sent to the DNS provider.",
+        &[("provider", provider), ("record", record), ("result", result_label(ok))],
+    );
+    if ok {
+        last_success(domain, record);
+    }
+}
+
+/// Time taken by one entry's provider requests.
+pub fn update_duration(provider: &str, duration: Duration) {
+    observe(
+        "ddns_update_duration_seconds",
+        "Time taken by the requests updating an entry at the DNS provider.",
+        &[("provider", provider)],
+        duration,
+    );
+}
+
+/// A check of `domain`'s `record` that left it up to date.
+pub fn last_success(domain: &str, record: &str) {
+    set(
+        "ddns_last_success_timestamp_seconds",
+        "Unix time of the last check that left the record up to date.",
+        &[("domain", domain), ("record", record)],
+        crate::state::now_secs() as f64,
+    );
+}
+
+/// Renders every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut out = String::new();
+    for (name, metric) in registry.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, metric.help);
+        let _ = writeln!(out, "# TYPE {} {}", name, metric.kind.name());
+        for (labels, value) in &metric.series {
+            match value {
+                Value::Number(n) => {
+                    let _ = writeln!(out, "{}{} {}", name, labels, n);
+                }
+                Value::Histogram { counts, sum, count } => {
+                    for (bucket, bound) in counts.iter().zip(BUCKETS) {
+                        let _ = writeln!(out, "{}_bucket{} {}", name, with_le(labels, &bound.to_string()), bucket);
+                    }
+                    let _ = writeln!(out, "{}_bucket{} {}", name, with_le(labels, "+Inf"), count);
+                    let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+                    let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Writes the metrics for node_exporter's textfile collector, replacing `path` atomically
+/// so the collector never reads a partial file.
+pub fn write_textfile(path: &Path) -> io::Result<()> {
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, render())?;
+    fs::rename(&tmp, path)
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn with_le(labels: &str, bound: &str) -> String {
+    match labels.strip_suffix('}') {
+        Some(labels) => format!("{},le=\"{}\"}}", labels, bound),
+        None => format!("{{le=\"{}\"}}", bound),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The lines of the rendered metrics that belong to `name`; the registry is shared by
+    /// every test in the process.
+    fn rendered(name: &str) -> Vec<String> {
+        render()
+            .lines()
+            .filter(|line| line.split([' ', '{']).any(|word| word.starts_with(name)))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+        assert_eq!(render_labels(&[]), "");
+        assert_eq!(render_labels(&[("a", "1"), ("b", "x\"y")]), r#"{a="1",b="x\"y"}"#);
+        assert_eq!(with_le("", "0.5"), r#"{le="0.5"}"#);
+        assert_eq!(with_le(r#"{a="1"}"#, "+Inf"), r#"{a="1",le="+Inf"}"#);
+    }
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        increment("test_render_total", "A counter.", &[("domain", "a.example.com")]);
+        increment("test_render_total", "A counter.", &[("domain", "a.example.com")]);
+        set("test_render_gauge", "A gauge.", &[], 2.5);
+        assert_eq!(
+            rendered("test_render_"),
+            [
+                "# HELP test_render_gauge A gauge.",
+                "# TYPE test_render_gauge gauge",
+                "test_render_gauge 2.5",
+                "# HELP test_render_total A counter.",
+                "# TYPE test_render_total counter",
+                r#"test_render_total{domain="a.example.com"} 2"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_histogram_buckets() {
+        let labels = [("provider", "test")];
+        for millis in [40, 100, 3000, 90_000] {
+            observe("test_histogram_seconds", "A histogram.", &labels, Duration::from_millis(millis));
+        }
+        let lines = rendered("test_histogram_seconds");
+        let bucket = |le: &str| {
+            let prefix = format!(r#"test_histogram_seconds_bucket{{provider="test",le="{}"}} "#, le);
+            lines
+                .iter()
+                .find_map(|line| line.strip_prefix(&prefix))
+                .unwrap_or_else(|| panic!("no bucket {} in {:?}", le, lines))
+                .to_string()
+        };
+        assert_eq!(bucket("0.05"), "1");
+        // Bounds are inclusive
+        assert_eq!(bucket("0.1"), "2");
+        assert_eq!(bucket("2.5"), "2");
+        assert_eq!(bucket("5"), "3");
+        assert_eq!(bucket("60"), "3");
+        assert_eq!(bucket("+Inf"), "4");
+        assert!(lines.contains(&r#"test_histogram_seconds_count{provider="test"} 4"#.to_string()));
+        assert!(lines.contains(&r#"test_histogram_seconds_sum{provider="test"} 93.14"#.to_string()));
+    }
+
+    #[test]
+    fn replaces_the_textfile() {
+        let dir = std::env::temp_dir().join(format!("rust-ddns-metrics-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ddns.prom");
+        fs::write(&path, "stale").unwrap();
+        set("test_textfile_gauge", "A gauge.", &[], 1.0);
+
+        write_textfile(&path).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("test_textfile_gauge 1\n"), "{}", contents);
+        assert!(!path.with_extension("prom.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fmt::{Display, Formatter},
+    io,
+    net::IpAddr,
+};
+
+/// A global address appearing on or disappearing from a network interface
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressChange {
+    pub interface: String,
+    pub address: IpAddr,
+    pub added: bool,
+}
+
+impl Display for AddressChange {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let action = if self.added { "added to" } else { "removed from" };
+        write!(f, "{} {} {}", self.address, action, self.interface)
+    }
+}
+
+/// Watches the kernel's rtnetlink address notifications (`RTMGRP_IPV4_IFADDR` and
+/// `RTMGRP_IPV6_IFADDR`)
+#[cfg(target_os = "linux")]
+pub struct AddressMonitor {
+    socket: tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>,
+    interfaces: Option<Vec<String>>,
+    buf: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl AddressMonitor {
+    /// Subscribes to address changes on `interfaces`, or on every interface if `None`.
+    pub fn new(interfaces: Option<Vec<String>>) -> io::Result<AddressMonitor> {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = (libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        let bound = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(AddressMonitor {
+            socket: tokio::io::unix::AsyncFd::new(socket)?,
+            interfaces,
+            buf: vec![0u8; 16384],
+        })
+    }
+
+    /// Waits for the next relevant address change.
+    ///
+    /// Only global-scope addresses count; temporary, tentative and DAD-failed IPv6
+    /// addresses are ignored, since they are not published. If the kernel dropped
+    /// notifications because the socket buffer overflowed, a change is assumed.
+    pub async fn changed(&mut self) -> io::Result<Option<AddressChange>> {
+        use std::os::fd::AsRawFd;
+
+        loop {
+            let mut guard = self.socket.readable().await?;
+            let buf = &mut self.buf;
+            let received = guard.try_io(|socket| {
+                let len = unsafe {
+                    libc::recv(
+                        socket.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if len < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(len as usize)
+                }
+            });
+            let len = match received {
+                Ok(Ok(len)) => len,
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::ENOBUFS) => return Ok(None),
+                Ok(Err(err)) => return Err(err),
+                Err(_would_block) => continue,
+            };
+            let changes = parse_messages(&self.buf[..len]);
+            if let Some(change) = changes.into_iter().find(|c| self.is_watched(c)) {
+                return Ok(Some(change));
+            }
+        }
+    }
+
+    /// Replaces the interfaces that are watched, e.g. after the config was reloaded.
+    pub fn watch(&mut self, interfaces: Option<Vec<String>>) {
+        self.interfaces = interfaces;
+    }
+
+    fn is_watched(&self, change: &AddressChange) -> bool {
+        match &self.interfaces {
+            Some(interfaces) => interfaces.contains(&change.interface),
+            None => true,
+        }
+    }
+}
+
+/// Extracts the relevant `RTM_NEWADDR`/`RTM_DELADDR` notifications from a netlink datagram.
+#[cfg(target_os = "linux")]
+fn parse_messages(buf: &[u8]) -> Vec<AddressChange> {
+    const HEADER_LEN: usize = 16;
+    const IFADDRMSG_LEN: usize = 8;
+    const RT_SCOPE_UNIVERSE: u8 = 0;
+    const IFA_ADDRESS: u16 = 1;
+    const IFA_LOCAL: u16 = 2;
+    const IFA_FLAGS: u16 = 8;
+    const IFA_F_TEMPORARY: u32 = 0x01;
+    const IFA_F_DADFAILED: u32 = 0x08;
+    const IFA_F_TENTATIVE: u32 = 0x40;
+    const UNPUBLISHED: u32 = IFA_F_TEMPORARY | IFA_F_DADFAILED | IFA_F_TENTATIVE;
+
+    let mut changes = Vec::new();
+    let mut offset = 0;
+    while offset + HEADER_LEN <= buf.len() {
+        let len = u32::from_ne_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]) as usize;
+        let kind = u16::from_ne_bytes([buf[offset + 4], buf[offset + 5]]);
+        if len < HEADER_LEN || offset + len > buf.len() {
+            break;
+        }
+        let message = &buf[offset + HEADER_LEN..offset + len];
+        offset += align(len);
+
+        if (kind != libc::RTM_NEWADDR && kind != libc::RTM_DELADDR) || message.len() < IFADDRMSG_LEN {
+            continue;
+        }
+        let family = message[0] as libc::c_int;
+        let mut flags = message[2] as u32;
+        let scope = message[3];
+        let index = u32::from_ne_bytes([message[4], message[5], message[6], message[7]]);
+
+        // IFA_LOCAL is the interface's own address on point-to-point links, where
+        // IFA_ADDRESS is the peer's
+        let mut address = None;
+        let mut local = None;
+        let mut attributes = &message[IFADDRMSG_LEN..];
+        while attributes.len() >= 4 {
+            let attribute_len = u16::from_ne_bytes([attributes[0], attributes[1]]) as usize;
+            let attribute_type = u16::from_ne_bytes([attributes[2], attributes[3]]);
+            if attribute_len < 4 || attribute_len > attributes.len() {
+                break;
+            }
+            let value = &attributes[4..attribute_len];
+            match attribute_type {
+                IFA_ADDRESS => address = decode_address(family, value),
+                IFA_LOCAL => local = decode_address(family, value),
+                IFA_FLAGS if value.len() >= 4 => {
+                    flags = u32::from_ne_bytes([value[0], value[1], value[2], value[3]]);
+                }
+                _ => {}
+            }
+            attributes = &attributes[align(attribute_len).min(attributes.len())..];
+        }
+
+        let address = match local.or(address) {
+            Some(address) => address,
+            None => continue,
+        };
+        if scope != RT_SCOPE_UNIVERSE || flags & UNPUBLISHED != 0 {
+            continue;
+        }
+        changes.push(AddressChange {
+            interface: interface_name(index),
+            address,
+            added: kind == libc::RTM_NEWADDR,
+        });
+    }
+    changes
+}
+
+#[cfg(target_os = "linux")]
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(target_os = "linux")]
+fn decode_address(family: libc::c_int, value: &[u8]) -> Option<IpAddr> {
+    match family {
+        libc::AF_INET => <[u8; 4]>::try_from(value).ok().map(IpAddr::from),
+        libc::AF_INET6 => <[u8; 16]>::try_from(value).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+/// Looks up an interface name by index; a removed interface is shown by its index.
+#[cfg(target_os = "linux")]
+fn interface_name(index: u32) -> String {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let result = unsafe { libc::if_indextoname(index, name.as_mut_ptr()) };
+    if result.is_null() {
+        return format!("#{}", index);
+    }
+    unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Address notifications are only available on Linux; elsewhere the daemon just polls
+#[cfg(not(target_os = "linux"))]
+pub struct AddressMonitor;
+
+#[cfg(not(target_os = "linux"))]
+impl AddressMonitor {
+    pub fn new(_interfaces: Option<Vec<String>>) -> io::Result<AddressMonitor> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "address notifications are only supported on Linux",
+        ))
+    }
+
+    pub fn watch(&mut self, _interfaces: Option<Vec<String>>) {}
+
+    pub async fn changed(&mut self) -> io::Result<Option<AddressChange>> {
+        std::future::pending().await
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    /// An index no interface has, so the name comes out as `#<index>`
+    const INDEX: u32 = 999_999;
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut buf = ((4 + value.len()) as u16).to_ne_bytes().to_vec();
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(value);
+        buf.resize(align(buf.len()), 0);
+        buf
+    }
+
+    /// An `RTM_NEWADDR`/`RTM_DELADDR` message with an `ifaddrmsg` and `attributes`
+    fn message(kind: u16, family: u8, flags: u8, scope: u8, attributes: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = [family, 64, flags, scope]
+            .into_iter()
+            .chain(INDEX.to_ne_bytes())
+            .chain(attributes.concat())
+            .collect();
+        let mut buf = ((16 + body.len()) as u32).to_ne_bytes().to_vec();
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(&[0; 10]);
+        buf.extend_from_slice(&body);
+        buf
+    }
+
+    #[test]
+    fn parses_added_and_removed_addresses() {
+        let v6: std::net::Ipv6Addr = "2001:db8::5".parse().unwrap();
+        let mut buf = message(libc::RTM_NEWADDR, libc::AF_INET as u8, 0, 0, &[attribute(1, &[203, 0, 113, 5])]);
+        buf.extend(message(libc::RTM_DELADDR, libc::AF_INET6 as u8, 0, 0, &[attribute(1, &v6.octets())]));
+        assert_eq!(
+            parse_messages(&buf),
+            vec![
+                AddressChange {
+                    interface: format!("#{}", INDEX),
+                    address: IpAddr::from([203, 0, 113, 5]),
+                    added: true,
+                },
+                AddressChange {
+                    interface: format!("#{}", INDEX),
+                    address: IpAddr::V6(v6),
+                    added: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn prefers_local_address() {
+        // Point-to-point link: IFA_ADDRESS is the peer, IFA_LOCAL our own address
+        let attributes = [attribute(1, &[198, 51, 100, 1]), attribute(2, &[198, 51, 100, 2])];
+        let buf = message(libc::RTM_NEWADDR, libc::AF_INET as u8, 0, 0, &attributes);
+        assert_eq!(parse_messages(&buf)[0].address, IpAddr::from([198, 51, 100, 2]));
+    }
+
+    #[test]
+    fn skips_unpublished_addresses() {
+        let address = attribute(1, &[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let family = libc::AF_INET6 as u8;
+        // Link scope
+        assert!(parse_messages(&message(libc::RTM_NEWADDR, family, 0, 253, std::slice::from_ref(&address))).is_empty());
+        // Temporary, in the ifaddrmsg flags and in IFA_FLAGS
+        assert!(parse_messages(&message(libc::RTM_NEWADDR, family, 0x01, 0, std::slice::from_ref(&address))).is_empty());
+        let flags = attribute(8, &0x40u32.to_ne_bytes());
+        assert!(parse_messages(&message(libc::RTM_NEWADDR, family, 0, 0, &[address, flags])).is_empty());
+    }
+
+    #[test]
+    fn skips_other_messages() {
+        // RTM_NEWLINK
+        let buf = message(16, libc::AF_INET as u8, 0, 0, &[attribute(1, &[203, 0, 113, 5])]);
+        assert!(parse_messages(&buf).is_empty());
+        // No address attribute
+        assert!(parse_messages(&message(libc::RTM_NEWADDR, libc::AF_INET as u8, 0, 0, &[])).is_empty());
+        // Truncated
+        let buf = message(libc::RTM_NEWADDR, libc::AF_INET as u8, 0, 0, &[attribute(1, &[203, 0, 113, 5])]);
+        assert!(parse_messages(&buf[..buf.len() - 4]).is_empty());
+    }
+}
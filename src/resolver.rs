@@ -0,0 +1,630 @@
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time,
+};
+
+use crate::{error, logging};
+
+const UDP_TIMEOUT: Duration = Duration::from_secs(2);
+const UDP_ATTEMPTS: usize = 3;
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_POINTERS: usize = 64;
+
+/// DNS record types used by this crate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Ns,
+    Txt,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(&self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Ns => 2,
+            RecordType::Txt => 16,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+impl Display for RecordType {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            RecordType::A => write!(f, "A"),
+            RecordType::Ns => write!(f, "NS"),
+            RecordType::Txt => write!(f, "TXT"),
+            RecordType::Aaaa => write!(f, "AAAA"),
+        }
+    }
+}
+
+/// DNS query classes; `Chaos` is used by `whoami.cloudflare`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Internet,
+    Chaos,
+}
+
+impl Class {
+    fn code(&self) -> u16 {
+        match self {
+            Class::Internet => 1,
+            Class::Chaos => 3,
+        }
+    }
+}
+
+/// The data of a resource record, for the types this crate understands
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Ns(String),
+    Txt(Vec<String>),
+    Other(u16),
+}
+
+/// A resource record from the answer section
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+/// A parsed DNS response
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// RCODE: 0 is NOERROR, 3 is NXDOMAIN
+    pub rcode: u8,
+    pub answers: Vec<ResourceRecord>,
+}
+
+impl Response {
+    /// Returns the addresses in the answer section, in order.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.answers
+            .iter()
+            .filter_map(|r| match r.data {
+                RecordData::A(ip) => Some(IpAddr::V4(ip)),
+                RecordData::Aaaa(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns every character-string of every TXT record in the answer section.
+    pub fn txt(&self) -> Vec<String> {
+        self.answers
+            .iter()
+            .filter_map(|r| match &r.data {
+                RecordData::Txt(strings) => Some(strings.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+}
+
+/// Sends a single question to `server` and returns the parsed response.
+///
+/// The query goes over UDP (retried on timeout) and is repeated over TCP if the
+/// response is truncated.
+///
+/// # Errors
+///
+/// Returns an error on network failure, timeout or a malformed response. DNS-level
+/// failures such as NXDOMAIN are *not* errors; check `Response::rcode`.
+pub async fn query(
+    server: SocketAddr,
+    name: &str,
+    record_type: RecordType,
+    class: Class,
+    recursion_desired: bool,
+) -> Result<Response, error::DynamicError> {
+    let id = query_id();
+    let packet = build_query(id, name, record_type, class, recursion_desired)?;
+
+    let bind_address = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(server).await?;
+    let mut buf = [0u8; 4096];
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send(&packet).await?;
+        let len = match time::timeout(UDP_TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(received) => received?,
+            Err(_) => continue,
+        };
+        if len < 12 || u16::from_be_bytes([buf[0], buf[1]]) != id {
+            continue;
+        }
+        let truncated = buf[2] & 0x02 != 0;
+        if truncated {
+            return query_tcp(server, &packet, id).await;
+        }
+        return parse_response(&buf[..len]);
+    }
+    Err(format!("DNS query for {} {} to {} timed out", name, record_type, server).into())
+}
+
+async fn query_tcp(server: SocketAddr, packet: &[u8], id: u16) -> Result<Response, error::DynamicError> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(server).await?;
+        stream.write_all(&(packet.len() as u16).to_be_bytes()).await?;
+        stream.write_all(packet).await?;
+        let len = stream.read_u16().await? as usize;
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).await?;
+        Ok::<Vec<u8>, std::io::Error>(buf)
+    };
+    let buf = match time::timeout(TCP_TIMEOUT, exchange).await {
+        Ok(buf) => buf?,
+        Err(_) => return Err(format!("DNS query over TCP to {} timed out", server).into()),
+    };
+    if buf.len() < 12 || u16::from_be_bytes([buf[0], buf[1]]) != id {
+        return Err(format!("malformed DNS response over TCP from {}", server).into());
+    }
+    parse_response(&buf)
+}
+
+fn query_id() -> u16 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    (nanos ^ (nanos >> 16) ^ std::process::id()) as u16
+}
+
+fn build_query(
+    id: u16,
+    name: &str,
+    record_type: RecordType,
+    class: Class,
+    recursion_desired: bool,
+) -> Result<Vec<u8>, error::DynamicError> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.push(if recursion_desired { 0x01 } else { 0x00 });
+    packet.push(0x00);
+    // QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid domain name '{}'", name).into());
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&record_type.code().to_be_bytes());
+    packet.extend_from_slice(&class.code().to_be_bytes());
+    Ok(packet)
+}
+
+fn parse_response(buf: &[u8]) -> Result<Response, error::DynamicError> {
+    let rcode = buf[3] & 0x0f;
+    let count = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]) as usize;
+    let (questions, answer_count) = (count(4), count(6));
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (_, next) = read_name(buf, offset)?;
+        offset = next + 4;
+    }
+    let mut answers = Vec::new();
+    for _ in 0..answer_count {
+        let (record, next) = read_record(buf, offset)?;
+        answers.push(record);
+        offset = next;
+    }
+    Ok(Response { rcode, answers })
+}
+
+fn read_record(buf: &[u8], offset: usize) -> Result<(ResourceRecord, usize), error::DynamicError> {
+    let (name, offset) = read_name(buf, offset)?;
+    let header = buf
+        .get(offset..offset + 10)
+        .ok_or("truncated DNS resource record")?;
+    let record_type = u16::from_be_bytes([header[0], header[1]]);
+    let ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+    let start = offset + 10;
+    let rdata = buf
+        .get(start..start + length)
+        .ok_or("truncated DNS record data")?;
+
+    let data = match record_type {
+        1 if length == 4 => RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+        28 if length == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(rdata);
+            RecordData::Aaaa(Ipv6Addr::from(octets))
+        }
+        2 => RecordData::Ns(read_name(buf, start)?.0),
+        5 => RecordData::Cname(read_name(buf, start)?.0),
+        16 => {
+            let mut strings = Vec::new();
+            let mut i = 0;
+            while i < rdata.len() {
+                let len = rdata[i] as usize;
+                let text = rdata.get(i + 1..i + 1 + len).ok_or("truncated TXT record")?;
+                strings.push(String::from_utf8_lossy(text).to_string());
+                i += 1 + len;
+            }
+            RecordData::Txt(strings)
+        }
+        other => RecordData::Other(other),
+    };
+    Ok((ResourceRecord { name, ttl, data }, start + length))
+}
+
+/// Reads a possibly compressed domain name, returning it and the offset just past it.
+fn read_name(buf: &[u8], mut offset: usize) -> Result<(String, usize), error::DynamicError> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *buf.get(offset).ok_or("truncated DNS name")? as usize;
+        if len & 0xc0 == 0xc0 {
+            let low = *buf.get(offset + 1).ok_or("truncated DNS name pointer")? as usize;
+            if end.is_none() {
+                end = Some(offset + 2);
+            }
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return Err("DNS name compression loop".into());
+            }
+            offset = ((len & 0x3f) << 8) | low;
+            continue;
+        }
+        if len == 0 {
+            let end = end.unwrap_or(offset + 1);
+            return Ok((labels.join("."), end));
+        }
+        let label = buf
+            .get(offset + 1..offset + 1 + len)
+            .ok_or("truncated DNS label")?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        offset += 1 + len;
+    }
+}
+
+const MAX_CNAME_DEPTH: usize = 8;
+const MAX_AUTHORITATIVE_SERVERS: usize = 4;
+const FALLBACK_NAMESERVERS: [&str; 2] = ["1.1.1.1:53", "8.8.8.8:53"];
+
+/// Why a lookup did not produce a record set
+#[derive(Debug)]
+pub enum LookupError {
+    /// The name does not exist (NXDOMAIN)
+    NxDomain(String),
+    /// No nameserver could be reached or every nameserver failed (timeout, SERVFAIL, ...)
+    Network(String),
+}
+
+impl Display for LookupError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            LookupError::NxDomain(name) => write!(f, "domain '{}' does not exist (NXDOMAIN)", name),
+            LookupError::Network(err) => write!(f, "DNS lookup failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+/// A stub resolver that sends queries to a list of upstream nameservers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolver {
+    nameservers: Vec<SocketAddr>,
+    /// `false` when the nameservers are authoritative servers queried directly
+    recursive: bool,
+    /// Used when none of the nameservers can answer
+    fallback: Option<Box<Resolver>>,
+}
+
+impl Resolver {
+    /// Creates a resolver that asks `nameservers` in order, moving on when one fails.
+    pub fn new(nameservers: Vec<SocketAddr>) -> Resolver {
+        Resolver {
+            nameservers,
+            recursive: true,
+            fallback: None,
+        }
+    }
+
+    /// Creates a resolver using the nameservers in `/etc/resolv.conf`, or public
+    /// resolvers where none are configured (or on Windows).
+    pub fn system() -> Resolver {
+        let mut nameservers = Vec::new();
+        if cfg!(unix) {
+            let conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+            for line in conf.lines() {
+                let mut fields = line.split_whitespace();
+                if fields.next() != Some("nameserver") {
+                    continue;
+                }
+                // Scoped IPv6 addresses (fe80::1%eth0) are not supported by SocketAddr
+                if let Some(Ok(ip)) = fields.next().map(|f| f.parse::<IpAddr>()) {
+                    nameservers.push(SocketAddr::new(ip, 53));
+                }
+            }
+        }
+        if nameservers.is_empty() {
+            nameservers = FALLBACK_NAMESERVERS
+                .iter()
+                .filter_map(|n| n.parse().ok())
+                .collect();
+        }
+        Resolver::new(nameservers)
+    }
+
+    /// Creates a resolver that queries the authoritative nameservers of the zone containing
+    /// `domain` directly, bypassing any recursive cache, and falls back to `self` if they
+    /// cannot be reached.
+    ///
+    /// The zone is found by asking `self` for NS records of `domain` and each parent
+    /// domain in turn; the nameserver names are then resolved through `self`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no zone cut is found or none of its nameservers has an address.
+    pub async fn authoritative_for(&self, domain: &str) -> Result<Resolver, LookupError> {
+        let logger = logging::Logger::new();
+        let (zone, names) = self.zone_nameservers(domain).await?;
+        let mut nameservers = Vec::new();
+        for name in &names {
+            for record_type in [RecordType::A, RecordType::Aaaa] {
+                match self.lookup(name, record_type).await {
+                    Ok(ips) => nameservers.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, 53))),
+                    Err(err) => logger.debug(&format!("Could not resolve nameserver {}: {}", name, err)),
+                }
+            }
+            if nameservers.len() >= MAX_AUTHORITATIVE_SERVERS {
+                break;
+            }
+        }
+        if nameservers.is_empty() {
+            return Err(LookupError::Network(format!(
+                "none of the nameservers of zone '{}' ({}) could be resolved",
+                zone,
+                names.join(", ")
+            )));
+        }
+        logger.debug(&format!(
+            "Using authoritative nameservers {:?} of zone '{}' for '{}'",
+            nameservers, zone, domain
+        ));
+        Ok(Resolver {
+            nameservers,
+            recursive: false,
+            fallback: Some(Box::new(self.clone())),
+        })
+    }
+
+    /// Finds the closest enclosing zone of `domain`, returning its name and NS names.
+    async fn zone_nameservers(&self, domain: &str) -> Result<(String, Vec<String>), LookupError> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        let labels: Vec<&str> = domain.split('.').collect();
+        // Stop before the top-level domain
+        for start in 0..labels.len().saturating_sub(1) {
+            let candidate = labels[start..].join(".");
+            let response = match self.query(&candidate, RecordType::Ns).await {
+                Ok(response) => response,
+                Err(LookupError::NxDomain(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            let names: Vec<String> = response
+                .answers
+                .iter()
+                .filter(|r| r.name == candidate)
+                .filter_map(|r| match &r.data {
+                    RecordData::Ns(name) => Some(name.clone()),
+                    _ => None,
+                })
+                .collect();
+            if !names.is_empty() {
+                return Ok((candidate, names));
+            }
+        }
+        Err(LookupError::Network(format!("could not find the zone of '{}'", domain)))
+    }
+
+    /// Looks up the A or AAAA record set of `name`, following CNAMEs.
+    ///
+    /// An empty set means the name exists but has no records of `record_type`. If the
+    /// resolver has a fallback, it is used when the lookup fails with a network error.
+    ///
+    /// # Errors
+    ///
+    /// Returns `LookupError::NxDomain` if the name (or a CNAME target) does not exist,
+    /// and `LookupError::Network` if no nameserver gave a usable answer.
+    pub async fn lookup(&self, name: &str, record_type: RecordType) -> Result<Vec<IpAddr>, LookupError> {
+        match (self.lookup_addresses(name, record_type).await, &self.fallback) {
+            (Err(LookupError::Network(err)), Some(fallback)) => {
+                let logger = logging::Logger::new();
+                logger.warning(&format!(
+                    "Authoritative lookup of '{}' failed ({}); falling back to the recursive resolver",
+                    name, err
+                ));
+                Box::pin(fallback.lookup(name, record_type)).await
+            }
+            (result, _) => result,
+        }
+    }
+
+    async fn lookup_addresses(&self, name: &str, record_type: RecordType) -> Result<Vec<IpAddr>, LookupError> {
+        let mut current = name.trim_end_matches('.').to_lowercase();
+        for _ in 0..MAX_CNAME_DEPTH {
+            let response = self.query(&current, record_type).await?;
+            // Follow the CNAME chain within the answer section
+            let mut owner = current.clone();
+            for _ in 0..MAX_CNAME_DEPTH {
+                let target = response.answers.iter().find_map(|r| match &r.data {
+                    RecordData::Cname(target) if r.name == owner => Some(target.clone()),
+                    _ => None,
+                });
+                match target {
+                    Some(target) => owner = target,
+                    None => break,
+                }
+            }
+            let addresses: Vec<IpAddr> = response
+                .answers
+                .iter()
+                .filter(|r| r.name == owner)
+                .filter_map(|r| match (record_type, &r.data) {
+                    (RecordType::A, RecordData::A(ip)) => Some(IpAddr::V4(*ip)),
+                    (RecordType::Aaaa, RecordData::Aaaa(ip)) => Some(IpAddr::V6(*ip)),
+                    _ => None,
+                })
+                .collect();
+            if !addresses.is_empty() || owner == current {
+                return Ok(addresses);
+            }
+            // The answer stopped at a CNAME; resolve its target separately
+            current = owner;
+        }
+        Err(LookupError::Network(format!("too many CNAMEs resolving '{}'", name)))
+    }
+
+    async fn query(&self, name: &str, record_type: RecordType) -> Result<Response, LookupError> {
+        let mut errors = Vec::new();
+        for nameserver in &self.nameservers {
+            match query(*nameserver, name, record_type, Class::Internet, self.recursive).await {
+                Ok(response) if response.rcode == 0 => return Ok(response),
+                Ok(response) if response.rcode == 3 => return Err(LookupError::NxDomain(name.to_string())),
+                Ok(response) => errors.push(format!("{} answered with rcode {}", nameserver, response.rcode)),
+                Err(err) => errors.push(err.to_string()),
+            }
+        }
+        Err(LookupError::Network(errors.join("; ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to `build_query(0x1234, "example.com", ..)` with `answers` appended. The
+    /// question name starts at offset 12, so answers can point at it with `0xc0, 0x0c`.
+    fn response(rcode: u8, answer_count: u16, answers: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x81, 0x80 | rcode, 0, 1];
+        buf.extend_from_slice(&answer_count.to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(b"\x07example\x03com\x00");
+        buf.extend_from_slice(&[0, 1, 0, 1]);
+        buf.extend_from_slice(answers);
+        buf
+    }
+
+    /// A resource record owned by the question name
+    fn record(record_type: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0xc0, 0x0c];
+        buf.extend_from_slice(&record_type.to_be_bytes());
+        buf.extend_from_slice(&[0, 1]);
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(rdata);
+        buf
+    }
+
+    #[test]
+    fn builds_query() {
+        let packet = build_query(0x1234, "Example.com.", RecordType::Aaaa, Class::Internet, true).unwrap();
+        assert_eq!(
+            packet,
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07Example\x03com\x00\x00\x1c\x00\x01"
+        );
+        let packet = build_query(1, "whoami.cloudflare", RecordType::Txt, Class::Chaos, false).unwrap();
+        assert_eq!(packet[2], 0x00);
+        assert_eq!(&packet[packet.len() - 4..], &[0, 16, 0, 3]);
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        assert!(build_query(1, "a..example.com", RecordType::A, Class::Internet, true).is_err());
+        let long = format!("{}.com", "a".repeat(64));
+        assert!(build_query(1, &long, RecordType::A, Class::Internet, true).is_err());
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let mut answers = record(1, 300, &[192, 0, 2, 1]);
+        let v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        answers.extend(record(28, 60, &v6.octets()));
+        let parsed = parse_response(&response(0, 2, &answers)).unwrap();
+        assert_eq!(parsed.rcode, 0);
+        assert_eq!(
+            parsed.answers[0],
+            ResourceRecord {
+                name: "example.com".to_string(),
+                ttl: 300,
+                data: RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            }
+        );
+        assert_eq!(
+            parsed.addresses(),
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V6(v6)]
+        );
+    }
+
+    #[test]
+    fn parses_compressed_names() {
+        // www.example.com as "www" plus a pointer to the question name
+        let answers = record(5, 60, b"\x03www\xc0\x0c");
+        let parsed = parse_response(&response(0, 1, &answers)).unwrap();
+        assert_eq!(parsed.answers[0].data, RecordData::Cname("www.example.com".to_string()));
+        let answers = record(2, 60, b"\x03NS1\xc0\x0c");
+        let parsed = parse_response(&response(0, 1, &answers)).unwrap();
+        assert_eq!(parsed.answers[0].data, RecordData::Ns("ns1.example.com".to_string()));
+    }
+
+    #[test]
+    fn parses_txt_strings() {
+        let answers = record(16, 0, b"\x0a192.0.2.10\x00\x03abc");
+        let parsed = parse_response(&response(0, 1, &answers)).unwrap();
+        assert_eq!(parsed.txt(), vec!["192.0.2.10", "", "abc"]);
+    }
+
+    #[test]
+    fn keeps_rcode_and_unknown_types() {
+        let parsed = parse_response(&response(3, 0, &[])).unwrap();
+        assert_eq!(parsed.rcode, 3);
+        assert!(parsed.answers.is_empty());
+        let parsed = parse_response(&response(0, 1, &record(6, 0, &[0; 4]))).unwrap();
+        assert_eq!(parsed.answers[0].data, RecordData::Other(6));
+    }
+
+    #[test]
+    fn rejects_truncated_responses() {
+        let mut buf = response(0, 1, &record(1, 300, &[192, 0, 2, 1]));
+        buf.truncate(buf.len() - 2);
+        assert!(parse_response(&buf).is_err());
+        assert!(parse_response(&response(0, 1, &record(16, 0, b"\x05ab"))).is_err());
+        assert!(parse_response(&response(0, 1, &[])).is_err());
+    }
+
+    #[test]
+    fn rejects_compression_loops() {
+        // A name that points at itself
+        let mut buf = response(0, 1, &[]);
+        let offset = buf.len() as u8;
+        buf.extend_from_slice(&[0xc0, offset]);
+        assert!(parse_response(&buf).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::logging::Logger;
+
+/// The last address successfully pushed to a provider for one domain and record type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Published {
+    pub ip: IpAddr,
+    /// Seconds since the Unix epoch
+    pub updated_at: u64,
+}
+
+impl Published {
+    /// Seconds since this address was published.
+    pub fn age_secs(&self) -> u64 {
+        now_secs().saturating_sub(self.updated_at)
+    }
+}
+
+/// Persistent record of the addresses published by previous runs, keyed by
+/// provider, domain and record type
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, Published>,
+}
+
+impl State {
+    /// Loads the state file at `path`, starting empty if it does not exist or cannot be parsed.
+    ///
+    /// The state is written back to the same path by `publish`.
+    pub fn load(path: PathBuf) -> State {
+        let logger = Logger::new();
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<State>(&contents) {
+                Ok(state) => state.entries,
+                Err(err) => {
+                    logger.warning(&format!(
+                        "Ignoring unreadable state file {}: {}",
+                        path.display(),
+                        err
+                    ));
+                    BTreeMap::new()
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        logger.debug(&format!("Using state file '{}'", path.display()));
+        State {
+            path: Some(path),
+            entries,
+        }
+    }
+
+    /// Returns what was last published for `server`, `domain` and `record`.
+    pub fn get(&self, server: &str, domain: &str, record: &str) -> Option<&Published> {
+        self.entries.get(&key(server, domain, record))
+    }
+
+    /// Records that `ip` was just published. `save` writes it to the state file.
+    pub fn publish(&mut self, server: &str, domain: &str, record: &str, ip: IpAddr) {
+        self.entries.insert(
+            key(server, domain, record),
+            Published {
+                ip,
+                updated_at: now_secs(),
+            },
+        );
+    }
+
+    /// Adds `entries` saved by other runs, keeping the newer of each.
+    fn merge(&mut self, entries: BTreeMap<String, Published>) {
+        for (key, published) in entries {
+            match self.entries.get(&key) {
+                Some(ours) if ours.updated_at >= published.updated_at => {}
+                _ => {
+                    self.entries.insert(key, published);
+                }
+            }
+        }
+    }
+}
+
+/// Saves `state` to its file, together with what other runs saved to it in the meantime.
+///
+/// The file IO and the wait for the lock run on the blocking thread pool, so a slow disk
+/// or another run holding the lock does not stall the checks of the other entries.
+pub async fn save(state: &RefCell<State>) {
+    let (path, entries) = match &state.borrow().path {
+        Some(path) => (path.clone(), state.borrow().entries.clone()),
+        None => return,
+    };
+    let target = path.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut state = State {
+            path: None,
+            entries,
+        };
+        sync(&target, &mut state).map(|_| state.entries)
+    })
+    .await
+    .unwrap_or_else(|err| Err(io::Error::other(err)));
+    match result {
+        Ok(saved) => state.borrow_mut().merge(saved),
+        Err(err) => Logger::new().error(&format!("Could not write state file {}: {}", path.display(), err)),
+    }
+}
+
+/// Merges the entries saved at `path` by other runs, such as the daemon and a one-shot
+/// run or the runs of other config files, into `state`, keeping the newer of each, then
+/// saves the result. Holding the lock next to the state file throughout keeps another run
+/// from saving in between, which would drop one of the two updates.
+fn sync(path: &Path, state: &mut State) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.with_extension("json.lock"))?;
+    lock.lock()?;
+    if let Ok(contents) = fs::read_to_string(path) {
+        if let Ok(saved) = serde_json::from_str::<State>(&contents) {
+            state.merge(saved.entries);
+        }
+    }
+    write(path, state)
+}
+
+fn key(server: &str, domain: &str, record: &str) -> String {
+    format!("{}/{}/{}", server, domain.to_lowercase(), record)
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Writes the state through a temporary file so a crash never leaves it half written.
+fn write(path: &Path, state: &State) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(state)?)?;
+    fs::rename(&tmp, path)
+}
+
+/// Returns the state file path: `DDNS_STATE_FILE` if set, otherwise `state.json` in the
+/// platform state directory.
+pub fn get_state_file_path() -> PathBuf {
+    match std::env::var("DDNS_STATE_FILE") {
+        Ok(path) if !path.is_empty() => PathBuf::from(path),
+        _ => state_dir().join("state.json"),
+    }
+}
+
+/// `$XDG_STATE_HOME/rust-ddns`, defaulting to `~/.local/state/rust-ddns`.
+#[cfg(not(target_os = "windows"))]
+pub fn state_dir() -> PathBuf {
+    match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("rust-ddns"),
+        _ => {
+            let home = std::env::var("HOME").unwrap_or_default();
+            PathBuf::from(home).join(".local/state/rust-ddns")
+        }
+    }
+}
+
+/// `%LOCALAPPDATA%\rust-ddns`.
+#[cfg(target_os = "windows")]
+pub fn state_dir() -> PathBuf {
+    let local_app_data = std::env::var("LOCALAPPDATA").unwrap_or_default();
+    PathBuf::from(local_app_data).join("rust-ddns")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state file path in a fresh directory of its own
+    fn state_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-ddns-state-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("state.json")
+    }
+
+    fn published(ip: &str, updated_at: u64) -> Published {
+        Published {
+            ip: ip.parse().unwrap(),
+            updated_at,
+        }
+    }
+
+    fn run(future: impl std::future::Future<Output = ()>) {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future);
+    }
+
+    #[test]
+    fn starts_empty_without_a_readable_file() {
+        let path = state_path("empty");
+        assert!(State::load(path.clone()).entries.is_empty());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "not json").unwrap();
+        assert!(State::load(path.clone()).entries.is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn saves_published_addresses() {
+        let path = state_path("publish");
+        let state = RefCell::new(State::load(path.clone()));
+        state
+            .borrow_mut()
+            .publish("cloudflare", "Home.Example.com", "A", "203.0.113.7".parse().unwrap());
+        run(save(&state));
+
+        let loaded = State::load(path.clone());
+        let entry = loaded.get("cloudflare", "home.example.com", "A").unwrap();
+        assert_eq!(entry.ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert!(entry.age_secs() < 60);
+        assert!(loaded.get("cloudflare", "home.example.com", "AAAA").is_none());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn merges_entries_saved_by_other_runs() {
+        let path = state_path("merge");
+        let state = RefCell::new(State::load(path.clone()));
+        {
+            let mut ours = state.borrow_mut();
+            ours.entries.insert("s/a/A".into(), published("192.0.2.1", 200));
+            ours.entries.insert("s/b/A".into(), published("192.0.2.2", 100));
+        }
+
+        // Another run saved in the meantime: a newer b, an older a and a new c
+        let mut other = State::default();
+        other.entries.insert("s/a/A".into(), published("198.51.100.1", 150));
+        other.entries.insert("s/b/A".into(), published("198.51.100.2", 300));
+        other.entries.insert("s/c/A".into(), published("198.51.100.3", 50));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, &other).unwrap();
+
+        run(save(&state));
+        let expected = BTreeMap::from([
+            ("s/a/A".to_string(), published("192.0.2.1", 200)),
+            ("s/b/A".to_string(), published("198.51.100.2", 300)),
+            ("s/c/A".to_string(), published("198.51.100.3", 50)),
+        ]);
+        assert_eq!(state.borrow().entries, expected);
+        assert_eq!(State::load(path.clone()).entries, expected);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
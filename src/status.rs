@@ -0,0 +1,264 @@
+use std::{cell::RefCell, io, net::IpAddr, rc::Rc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{logging::Logger, metrics, state::now_secs, time_tools};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// Outcome of the checks of one record, kept by its `APIClient`
+#[derive(Debug, Clone, Default)]
+pub struct RecordStatus {
+    pub checked_at: Option<u64>,
+    pub succeeded_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+    /// Start of the current run of failed checks, cleared by a successful one
+    pub failing_since: Option<u64>,
+}
+
+impl RecordStatus {
+    pub fn checked(&mut self) {
+        self.checked_at = Some(now_secs());
+    }
+
+    pub fn succeeded(&mut self) {
+        self.succeeded_at = Some(now_secs());
+        self.failing_since = None;
+    }
+
+    pub fn failed(&mut self, err: &str) {
+        let now = now_secs();
+        self.last_error = Some(err.to_string());
+        self.last_error_at = Some(now);
+        self.failing_since.get_or_insert(now);
+    }
+
+    /// Whether the latest check has already succeeded.
+    pub fn succeeded_since_check(&self) -> bool {
+        match (self.succeeded_at, self.checked_at) {
+            (Some(succeeded), Some(checked)) => succeeded >= checked,
+            _ => false,
+        }
+    }
+}
+
+/// Snapshot of one record for the status endpoint
+#[derive(Debug, Clone)]
+pub struct RecordReport {
+    pub server: String,
+    pub domain: String,
+    pub record: String,
+    pub detected: Option<IpAddr>,
+    pub published: Option<IpAddr>,
+    pub last_update: Option<u64>,
+    pub status: RecordStatus,
+}
+
+impl RecordReport {
+    /// Whether the record has been failing for at least `threshold` seconds.
+    fn unhealthy(&self, threshold: u64, now: u64) -> bool {
+        match self.status.failing_since {
+            Some(since) => now.saturating_sub(since) >= threshold,
+            None => false,
+        }
+    }
+
+    fn to_json(&self, threshold: u64, now: u64) -> serde_json::Value {
+        let time = |secs: Option<u64>| secs.map(time_tools::timestamp_as_string);
+        serde_json::json!({
+            "server": self.server,
+            "domain": self.domain,
+            "record": self.record,
+            "detected_ip": self.detected.map(|ip| ip.to_string()),
+            "published_ip": self.published.map(|ip| ip.to_string()),
+            "last_check": time(self.status.checked_at),
+            "last_update": time(self.last_update),
+            "last_error": self.status.last_error,
+            "last_error_at": time(self.status.last_error_at),
+            "failing_since": time(self.status.failing_since),
+            "healthy": !self.unhealthy(threshold, now),
+        })
+    }
+}
+
+/// Serves the status of `reports` over HTTP on `listener`:
+///
+/// * `/` and `/status` - JSON with every record's detected and published address, last
+///   check, last update and last error
+/// * `/healthz` - `200 ok`, or `503` once a record has been failing for `health_threshold`
+///   seconds
+/// * `/metrics` - the Prometheus metrics of this process
+///
+/// Must run inside a `LocalSet`; each connection is handled in its own local task.
+pub async fn serve(listener: TcpListener, reports: Rc<RefCell<Vec<RecordReport>>>, health_threshold: u64) {
+    let logger = Logger::new();
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let reports = Rc::clone(&reports);
+                tokio::task::spawn_local(async move {
+                    if let Err(err) = handle(stream, &reports, health_threshold).await {
+                        Logger::new().debug(&format!("Status request from {} failed: {}", peer, err));
+                    }
+                });
+            }
+            Err(err) => {
+                logger.warning(&format!("Status listener could not accept a connection: {}", err));
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    reports: &RefCell<Vec<RecordReport>>,
+    health_threshold: u64,
+) -> io::Result<()> {
+    let head = match time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")),
+    };
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default();
+
+    let (status, content_type, body) = if method != "GET" && method != "HEAD" {
+        ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_string())
+    } else {
+        route(path, &reports.borrow(), health_threshold, now_secs())
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    if method != "HEAD" {
+        response.push_str(&body);
+    }
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads the request line and headers; any body is ignored.
+async fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..len]);
+        if head.windows(4).any(|w| w == b"\r\n\r\n") || head.len() >= MAX_REQUEST_HEAD {
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn route(path: &str, reports: &[RecordReport], health_threshold: u64, now: u64) -> (&'static str, &'static str, String) {
+    match path {
+        "/" | "/status" => {
+            let records: Vec<serde_json::Value> = reports
+                .iter()
+                .map(|report| report.to_json(health_threshold, now))
+                .collect();
+            let body = serde_json::json!({
+                "healthy": !reports.iter().any(|r| r.unhealthy(health_threshold, now)),
+                "records": records,
+            });
+            ("200 OK", "application/json", format!("{:#}\n", body))
+        }
+        "/healthz" => {
+            let failing: Vec<String> = reports
+                .iter()
+                .filter(|r| r.unhealthy(health_threshold, now))
+                .map(|r| {
+                    format!(
+                        "{} {} failing since {}: {}\n",
+                        r.domain,
+                        r.record,
+                        r.status.failing_since.map(time_tools::timestamp_as_string).unwrap_or_default(),
+                        r.status.last_error.as_deref().unwrap_or_default()
+                    )
+                })
+                .collect();
+            if failing.is_empty() {
+                ("200 OK", "text/plain", "ok\n".to_string())
+            } else {
+                ("503 Service Unavailable", "text/plain", failing.concat())
+            }
+        }
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics::render()),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn report(domain: &str, failing_for: Option<u64>) -> RecordReport {
+        RecordReport {
+            server: "cloudflare".into(),
+            domain: domain.into(),
+            record: "A".into(),
+            detected: Some("203.0.113.7".parse().unwrap()),
+            published: Some("203.0.113.7".parse().unwrap()),
+            last_update: Some(NOW - 3600),
+            status: RecordStatus {
+                checked_at: Some(NOW),
+                failing_since: failing_for.map(|secs| NOW - secs),
+                last_error: failing_for.map(|_| "timed out".to_string()),
+                ..RecordStatus::default()
+            },
+        }
+    }
+
+    #[test]
+    fn reports_health_from_the_threshold() {
+        let healthy = [report("a.example.com", None), report("b.example.com", Some(599))];
+        let (status, _, body) = route("/healthz", &healthy, 600, NOW);
+        assert_eq!((status, body.as_str()), ("200 OK", "ok\n"));
+
+        let failing = [report("a.example.com", None), report("b.example.com", Some(600))];
+        let (status, _, body) = route("/healthz", &failing, 600, NOW);
+        assert_eq!(status, "503 Service Unavailable");
+        assert!(body.starts_with("b.example.com A failing since "), "{}", body);
+        assert!(body.ends_with(": timed out\n"), "{}", body);
+    }
+
+    #[test]
+    fn reports_status_as_json() {
+        let reports = [report("a.example.com", None), report("b.example.com", Some(600))];
+        for path in ["/", "/status"] {
+            let (status, content_type, body) = route(path, &reports, 600, NOW);
+            assert_eq!((status, content_type), ("200 OK", "application/json"));
+            let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(json["healthy"], false);
+            assert_eq!(json["records"][0]["healthy"], true);
+            assert_eq!(json["records"][1]["healthy"], false);
+            assert_eq!(json["records"][1]["last_error"], "timed out");
+            assert_eq!(json["records"][0]["published_ip"], "203.0.113.7");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_paths() {
+        let (status, _, body) = route("/nope", &[], 600, NOW);
+        assert_eq!((status, body.as_str()), ("404 Not Found", "not found\n"));
+        assert_eq!(route("/healthz/", &[], 600, NOW).0, "404 Not Found");
+    }
+}
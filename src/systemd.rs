@@ -0,0 +1,243 @@
+use std::{
+    cell::Cell,
+    io,
+    time::{Duration, Instant},
+};
+
+use crate::{logging::Logger, status::RecordReport, time_tools};
+
+/// Sends service state notifications to systemd over `$NOTIFY_SOCKET` (see sd_notify(3))
+#[cfg(target_os = "linux")]
+pub struct Notifier {
+    socket: std::os::unix::net::UnixDatagram,
+    address: std::os::unix::net::SocketAddr,
+}
+
+#[cfg(target_os = "linux")]
+impl Notifier {
+    /// Connects to the socket named by `NOTIFY_SOCKET`, or returns `None` when not run by
+    /// systemd.
+    pub fn from_env() -> io::Result<Option<Notifier>> {
+        match std::env::var("NOTIFY_SOCKET") {
+            Ok(path) if !path.is_empty() => Notifier::connect(&path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Prepares to send to the socket at `path`. A leading `@` names an abstract socket.
+    pub fn connect(path: &str) -> io::Result<Notifier> {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Notifier {
+            socket: std::os::unix::net::UnixDatagram::unbound()?,
+            address,
+        })
+    }
+
+    /// Sends `state`, one `KEY=value` assignment per line. Failures are only logged, since
+    /// the service keeps working without them.
+    pub fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            Logger::new().debug(&format!("Could not notify systemd ({}): {}", state.replace('\n', " "), err));
+        }
+    }
+}
+
+/// systemd notifications are only available on Linux
+#[cfg(not(target_os = "linux"))]
+pub struct Notifier;
+
+#[cfg(not(target_os = "linux"))]
+impl Notifier {
+    pub fn from_env() -> io::Result<Option<Notifier>> {
+        Ok(None)
+    }
+
+    pub fn notify(&self, _state: &str) {}
+}
+
+/// How often to send `WATCHDOG=1`: half the `WatchdogSec=` systemd passed in
+/// `WATCHDOG_USEC`, or `None` if the watchdog is off or meant for another process.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_of(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// The watchdog interval for the values of `WATCHDOG_USEC` and `WATCHDOG_PID`, as seen by
+/// the process `own_pid`.
+fn watchdog_interval_of(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(own_pid) {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// How long an update cycle or reload may run before the daemon counts as stalled. Every
+/// request of a cycle has its own timeout, so only a hung one takes this long.
+const STALL_LIMIT: Duration = Duration::from_secs(300);
+
+/// Whether the daemon's loop is busy with a cycle or reload, and since when
+#[derive(Debug, Default)]
+pub struct Progress {
+    busy_since: Cell<Option<Instant>>,
+}
+
+impl Progress {
+    /// Marks the start of an update cycle or reload.
+    pub fn start(&self) {
+        self.busy_since.set(Some(Instant::now()));
+    }
+
+    /// Marks the end of the cycle or reload; the loop is waiting for the next one.
+    pub fn finish(&self) {
+        self.busy_since.set(None);
+    }
+
+    /// How long the current cycle or reload has been running, if longer than `STALL_LIMIT`
+    fn stalled_for(&self) -> Option<Duration> {
+        self.busy_since
+            .get()
+            .map(|since| since.elapsed())
+            .filter(|elapsed| *elapsed > STALL_LIMIT)
+    }
+}
+
+/// Sends `WATCHDOG=1` every `interval` as long as the daemon's loop makes progress. Once a
+/// cycle or reload has run for longer than `STALL_LIMIT`, e.g. on a hung provider request,
+/// the pings stop so that systemd restarts the daemon.
+pub async fn watchdog(notifier: std::rc::Rc<Notifier>, interval: Duration, progress: std::rc::Rc<Progress>) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        match progress.stalled_for() {
+            Some(elapsed) => Logger::new().warning(&format!(
+                "Check running for {}s; withholding the watchdog ping",
+                elapsed.as_secs()
+            )),
+            None => notifier.notify("WATCHDOG=1"),
+        }
+    }
+}
+
+/// One-line summary of the last check for `STATUS=`, e.g.
+/// `3 records, 1 failing; last update example.com A at 2024-01-01T00:00:00+00:00`.
+pub fn describe(reports: &[RecordReport]) -> String {
+    let failing = reports.iter().filter(|r| r.status.failing_since.is_some()).count();
+    let mut status = format!("{} records, {} failing", reports.len(), failing);
+    let last_update = reports
+        .iter()
+        .filter_map(|r| r.last_update.map(|at| (at, r)))
+        .max_by_key(|(at, _)| *at);
+    match last_update {
+        Some((at, report)) => status.push_str(&format!(
+            "; last update {} {} {} at {}",
+            report.domain,
+            report.record,
+            report.published.map(|ip| ip.to_string()).unwrap_or_default(),
+            time_tools::timestamp_as_string(at)
+        )),
+        None => status.push_str("; no updates yet"),
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::RecordStatus;
+
+    fn report(domain: &str, last_update: Option<u64>, failing: bool) -> RecordReport {
+        RecordReport {
+            server: "cloudflare".to_string(),
+            domain: domain.to_string(),
+            record: "A".to_string(),
+            detected: None,
+            published: Some("203.0.113.7".parse().unwrap()),
+            last_update,
+            status: RecordStatus {
+                failing_since: failing.then_some(1),
+                ..RecordStatus::default()
+            },
+        }
+    }
+
+    #[test]
+    fn describes_reports() {
+        assert_eq!(describe(&[]), "0 records, 0 failing; no updates yet");
+        let reports = [
+            report("a.example.com", Some(1_600_000_000), false),
+            report("b.example.com", Some(1_704_067_200), true),
+            report("c.example.com", None, false),
+        ];
+        assert_eq!(
+            describe(&reports),
+            "3 records, 1 failing; last update b.example.com A 203.0.113.7 at 2024-01-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn reads_watchdog_interval() {
+        let half_minute = Some(Duration::from_secs(30));
+        assert_eq!(watchdog_interval_of(Some("60000000"), None, 42), half_minute);
+        assert_eq!(watchdog_interval_of(Some("60000000"), Some("42"), 42), half_minute);
+        // Meant for another process, e.g. the shell that started us
+        assert_eq!(watchdog_interval_of(Some("60000000"), Some("1"), 42), None);
+        assert_eq!(watchdog_interval_of(Some("60000000"), Some("self"), 42), None);
+        assert_eq!(watchdog_interval_of(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval_of(Some("soon"), None, 42), None);
+        assert_eq!(watchdog_interval_of(None, None, 42), None);
+    }
+
+    #[test]
+    fn detects_stalls() {
+        let progress = Progress::default();
+        assert_eq!(progress.stalled_for(), None);
+        progress.start();
+        assert_eq!(progress.stalled_for(), None);
+        let since = Instant::now() - STALL_LIMIT - Duration::from_secs(1);
+        progress.busy_since.set(Some(since));
+        assert!(progress.stalled_for().is_some_and(|elapsed| elapsed > STALL_LIMIT));
+        progress.finish();
+        assert_eq!(progress.stalled_for(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifies_socket() {
+        let path = std::env::temp_dir().join(format!("rust-ddns-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::connect(path.to_str().unwrap()).unwrap();
+        notifier.notify("READY=1\nSTATUS=Checking 2 entries");
+        let mut buf = [0u8; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Checking 2 entries");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifies_abstract_socket() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("rust-ddns-notify-{}", std::process::id());
+        let socket = std::os::unix::net::UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        Notifier::connect(&format!("@{}", name)).unwrap().notify("WATCHDOG=1");
+        let mut buf = [0u8; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+    }
+}
@@ -11,3 +11,57 @@ fn iso8601(st: &time::SystemTime) -> String {
     format!("{}", dt.format("%+"))
     // formats like "2001-07-08T00:34:60.026490+09:30"
 }
+
+/// Parses a duration such as `90`, `30s`, `5min`, `12h` or `30d` into seconds.
+pub fn parse_duration_secs(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, ""),
+    };
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "min" | "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Formats seconds since the Unix epoch like `now_as_string`.
+pub fn timestamp_as_string(secs: u64) -> String {
+    iso8601(&(time::UNIX_EPOCH + time::Duration::from_secs(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration_secs("90"), Some(90));
+        assert_eq!(parse_duration_secs("30s"), Some(30));
+        assert_eq!(parse_duration_secs("5min"), Some(300));
+        assert_eq!(parse_duration_secs("5m"), Some(300));
+        assert_eq!(parse_duration_secs("12h"), Some(43_200));
+        assert_eq!(parse_duration_secs(" 30d "), Some(2_592_000));
+        assert_eq!(parse_duration_secs("0"), Some(0));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert_eq!(parse_duration_secs(""), None);
+        assert_eq!(parse_duration_secs("h"), None);
+        assert_eq!(parse_duration_secs("5w"), None);
+        assert_eq!(parse_duration_secs("1.5h"), None);
+        assert_eq!(parse_duration_secs("-5"), None);
+        assert_eq!(parse_duration_secs("99999999999999999999d"), None);
+        assert_eq!(parse_duration_secs("999999999999999999d"), None);
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp_as_string(1_704_067_200), "2024-01-01T00:00:00+00:00");
+    }
+}